@@ -0,0 +1,103 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Context, Result};
+use qrcode::{render::unicode, QrCode};
+
+/// All the addresses the server can be reached at. When listening on an unspecified address
+/// (`0.0.0.0` or `::`), every address of the local interfaces is listed, loopback first.
+pub fn reachable_addrs(addr: SocketAddr) -> Vec<SocketAddr> {
+    if !addr.ip().is_unspecified() {
+        return vec![addr];
+    }
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("failed to list network interfaces: {}", e);
+            return vec![addr];
+        }
+    };
+    let mut ips = interfaces
+        .iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| accepts(addr.ip(), *ip))
+        .collect::<Vec<_>>();
+    ips.sort_by_key(|ip| (!ip.is_loopback(), ip.is_ipv6()));
+    ips.dedup();
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, addr.port()))
+        .collect()
+}
+
+/// The url to open in a browser, using `localhost` when listening on an unspecified address.
+pub fn browser_url(scheme: &str, addr: SocketAddr, start_path: &str) -> String {
+    if addr.ip().is_unspecified() {
+        let start_path = start_path.trim_start_matches('/');
+        format!("{}://localhost:{}/{}", scheme, addr.port(), start_path)
+    } else {
+        url(scheme, addr, start_path)
+    }
+}
+
+pub fn open_browser(url: &str) -> Result<()> {
+    debug!("opening browser at {}", url);
+    webbrowser::open(url).with_context(|| format!("failed to open browser at {}", url))
+}
+
+/// Render the url as a QR code, to be printed in a terminal.
+pub fn qr_code(url: &str) -> Result<String> {
+    let code = QrCode::new(url.as_bytes()).context("failed to generate QR code")?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+fn accepts(listening: IpAddr, ip: IpAddr) -> bool {
+    match (listening, ip) {
+        // link-local addresses can't be used without a scope id
+        (_, IpAddr::V6(v6)) if (v6.segments()[0] & 0xffc0) == 0xfe80 => false,
+        // on most systems, `::` also accepts ipv4 connections
+        (IpAddr::V6(_), _) => true,
+        (IpAddr::V4(_), ip) => ip.is_ipv4(),
+    }
+}
+
+pub fn url(scheme: &str, addr: SocketAddr, start_path: &str) -> String {
+    let start_path = start_path.trim_start_matches('/');
+    format!("{}://{}/{}", scheme, addr, start_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_urls() {
+        let addr: SocketAddr = "127.0.0.1:4242".parse().unwrap();
+        assert_eq!(reachable_addrs(addr), vec![addr]);
+        assert_eq!(url("http", addr, ""), "http://127.0.0.1:4242/");
+        assert_eq!(
+            browser_url("http", addr, "/app"),
+            "http://127.0.0.1:4242/app"
+        );
+        let addr: SocketAddr = "[::1]:4242".parse().unwrap();
+        assert_eq!(url("https", addr, "app"), "https://[::1]:4242/app");
+        let addr: SocketAddr = "0.0.0.0:4242".parse().unwrap();
+        assert_eq!(browser_url("http", addr, ""), "http://localhost:4242/");
+        assert!(reachable_addrs(addr)
+            .iter()
+            .all(|addr| addr.is_ipv4() && addr.port() == 4242));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let v4 = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+        let v6: IpAddr = "::".parse().unwrap();
+        assert!(accepts(v4, "192.168.1.2".parse().unwrap()));
+        assert!(!accepts(v4, "fd00::1".parse().unwrap()));
+        assert!(accepts(v6, "192.168.1.2".parse().unwrap()));
+        assert!(accepts(v6, "fd00::1".parse().unwrap()));
+        assert!(!accepts(v6, "fe80::1".parse().unwrap()));
+    }
+}
@@ -4,7 +4,29 @@ use std::{
 };
 
 use anyhow::{Context, Result};
-use rouille::url::percent_encoding::{percent_encode, USERINFO_ENCODE_SET};
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+
+/// Same as the `USERINFO_ENCODE_SET` of `url` 1.x, so the cached paths stay the same.
+const USERINFO_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'?')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b':')
+    .add(b';')
+    .add(b'=')
+    .add(b'@')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'|');
 
 #[derive(Debug)]
 pub struct Cache {
@@ -15,6 +37,7 @@ pub struct Cache {
 pub enum CacheKind {
     Archive,
     Http,
+    Recording,
 }
 
 impl CacheKind {
@@ -22,6 +45,7 @@ impl CacheKind {
         match self {
             CacheKind::Archive => "archive",
             CacheKind::Http => "http",
+            CacheKind::Recording => "recording",
         }
     }
 }
@@ -53,6 +77,17 @@ impl Cache {
         Ok(path)
     }
 
+    /// Same as [`resource`](Cache::resource), but keeps the previous content of the folder.
+    pub fn persistent_resource(&self, kind: CacheKind, parts: &[&[u8]]) -> Result<PathBuf> {
+        let mut path = self.cache_folder.join(kind.as_folder());
+        ensure_path_exists(&path)?;
+        for part in parts {
+            path = path.join(to_cached_path(part));
+            ensure_path_exists(&path)?
+        }
+        Ok(path)
+    }
+
     #[cfg(test)]
     #[allow(dead_code)]
     pub(crate) fn init_with_custom_path_for_test(cache_folder: PathBuf) -> Self {
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt, fs};
+use std::{collections::HashMap, fmt, fs, num::NonZeroUsize, str::FromStr};
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
@@ -8,8 +8,25 @@ pub struct Config {
     /// Configure the server
     pub server: ServerConfig,
     /// Configure the proxies. The keys represent the part that will be matched to test if a call
-    /// must be proxied, and they will always be matched at the beginning of the request's url.
+    /// must be proxied: a prefix of the request's path, a glob like `/api/*/ws`, or a regex
+    /// starting with `^`. When several proxies match, the one with the highest `priority` wins,
+    /// then the most specific path.
     pub proxies: HashMap<String, ProxyTarget>,
+    /// Configure mocked routes, always matched before the proxies. Useful to work on the
+    /// frontend while the backend is not available.
+    #[serde(default)]
+    pub mocks: Vec<MockRoute>,
+    /// Configure network profiles for some static files, matched with a glob on the request's
+    /// path. The first matching rule is used, and they take precedence over
+    /// [`throttle`](ServerConfig::throttle).
+    /// # Example
+    /// ```toml
+    /// [[throttles]]
+    /// path = "/assets/**/*.js"
+    /// profile = { kbps = 750, latency = 100 }
+    /// ```
+    #[serde(default)]
+    pub throttles: Vec<ThrottleRule>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,15 +45,88 @@ pub struct ServerConfig {
     /// ```
     #[serde(default)]
     pub base_path: Option<String>,
-    /// The port the application should listen on, defaults to [default_port](ServerConfig::default_port)
-    #[serde(default = "ServerConfig::default_port")]
+    /// The port the application should listen on, defaults to [default_port](ServerConfig::default_port).
+    /// It can be `"auto"` (or `0`) to let the OS choose a free port.
+    #[serde(
+        default = "ServerConfig::default_port",
+        deserialize_with = "deserialize_port"
+    )]
     pub port: u16,
-    /// The host the application should listen on, defaults to [default_host](ServerConfig::default_host)
+    /// Listen on several addresses, each one with its own host, port and optional TLS settings.
+    /// If set, `host` and `port` are ignored.
+    /// # Example
+    /// ```toml
+    /// [[server.listen]]
+    /// host = "127.0.0.1"
+    /// port = 4242
+    /// [[server.listen]]
+    /// host = "::1"
+    /// port = 4443
+    /// tls = { cert = "~/certs/localhost.pem", key = "~/certs/localhost-key.pem" }
+    /// ```
+    #[serde(default)]
+    pub listen: Vec<ListenConfig>,
+    /// The number of worker threads handling the connections, defaults to the number of CPUs.
+    /// Requests don't hold a thread while waiting, so long-lived proxied connections are cheap.
+    /// It can't be 0.
+    #[serde(default)]
+    pub pool_size: Option<NonZeroUsize>,
+    /// The maximum number of connections open at the same time on all the listeners, including
+    /// the ones streaming a response or upgraded to a websocket. The requests of the other
+    /// connections are rejected with a `503 Service Unavailable`, and they are closed. It can't
+    /// be 0. When it is set, the clients have 10 seconds to send the headers of each request, so
+    /// connections sending nothing don't keep their slot.
+    #[serde(default)]
+    pub max_connections: Option<NonZeroUsize>,
+    /// The maximum size of the request headers, in bytes. Bigger requests are rejected with a
+    /// `431 Request Header Fields Too Large`.
+    #[serde(default)]
+    pub max_header_size: Option<usize>,
+    /// The maximum size of the request bodies, in bytes. Bigger requests are rejected with a
+    /// `413 Payload Too Large`.
+    #[serde(default)]
+    pub max_body_size: Option<u64>,
+    /// Close the connections when nothing was sent or received for this many seconds between two
+    /// requests. A response still being prepared or streamed keeps the connection open.
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    /// If the port is already in use, try the next ones, and finally let the OS choose one.
+    #[serde(default)]
+    pub port_fallback: bool,
+    /// A file where the port actually used is written, for scripts that need to discover it.
+    #[serde(default)]
+    pub port_file: Option<String>,
+    /// The host the application should listen on, defaults to [default_host](ServerConfig::default_host).
+    /// It can be a unix domain socket, like `unix:/run/spa-server.sock`, and it is ignored if
+    /// sockets are passed by systemd (socket activation).
     #[serde(default = "ServerConfig::default_host")]
     pub host: String,
+    /// A network profile applied to all the static files, either `"slow-3g"`, `"fast-3g"`, or a
+    /// custom one like `{ kbps = 750, latency = 100 }`.
+    #[serde(default)]
+    pub throttle: Option<NetworkProfile>,
+    /// The path opened in the browser with the `--open` flag, defaults to `/`.
+    #[serde(default)]
+    pub start_path: Option<String>,
 }
 
 impl ServerConfig {
+    /// The configured listeners, or the one defined by `host` and `port`.
+    pub fn listeners(&self) -> Vec<ListenConfig> {
+        if self.listen.is_empty() {
+            vec![ListenConfig {
+                host: self.host.clone(),
+                port: self.port,
+                tls: None,
+            }]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    /// How many ports are tried when [`port_fallback`](ServerConfig::port_fallback) is set,
+    /// before letting the OS choose one.
+    pub const PORT_FALLBACK_ATTEMPTS: u16 = 10;
     fn default_port() -> u16 {
         4242
     }
@@ -45,18 +135,427 @@ impl ServerConfig {
     }
 }
 
-/// Currently, a proxy target can only be defined as a path to be matched, and an url to send the
-/// same request to. No path rewrite is supported at all.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenConfig {
+    /// Same as [`ServerConfig::host`](ServerConfig::host).
+    #[serde(default = "ServerConfig::default_host")]
+    pub host: String,
+    /// Same as [`ServerConfig::port`](ServerConfig::port).
+    #[serde(
+        default = "ServerConfig::default_port",
+        deserialize_with = "deserialize_port"
+    )]
+    pub port: u16,
+    /// Serve over https with this certificate.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    /// Path to the certificate (chain), in PEM format. It can contain the `~` and environment
+    /// variables.
+    pub cert: String,
+    /// Path to the private key, in PEM format. It can contain the `~` and environment variables.
+    pub key: String,
+}
+
+fn deserialize_port<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Port {
+        Number(u16),
+        Name(String),
+    }
+    match Port::deserialize(deserializer)? {
+        Port::Number(port) => Ok(port),
+        Port::Name(name) if name == "auto" => Ok(0),
+        Port::Name(name) => Err(serde::de::Error::custom(format!(
+            "invalid port `{}`, expected a number or \"auto\"",
+            name
+        ))),
+    }
+}
+
+/// A proxy target is defined by a path to be matched, and an url to send the same request to. The
+/// path of the request can be rewritten before being sent.
+/// # Example
+/// ```toml
+/// [proxies."/api"]
+/// target = "http://localhost:8080"
+/// # `/api/users/42` is sent to `http://localhost:8080/v2/users/42`
+/// path_rewrite = ["^/api/(.*)$", "/v2/$1"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
 pub struct ProxyTarget {
-    /// The target url (protocol, host, port, paths...).
+    /// The target url (protocol, host, port, paths...), or `unix:///run/app.sock` for a target
+    /// listening on a unix domain socket.
+    #[serde(default)]
     pub target: String,
-    /// Path rewriting, not used for now.
+    /// Several target urls to balance the requests between, instead of a single `target`.
+    #[serde(default)]
+    pub targets: Vec<String>,
+    /// How the target of a request is picked among `targets`: `round-robin` (the default),
+    /// `random`, or `first-healthy` for a primary and its fallbacks.
+    #[serde(default)]
+    pub balance: Balance,
+    /// A target is considered down after this many failures in a row to reach it, defaults to 3.
+    /// The requests then go to the other targets.
+    #[serde(default)]
+    pub max_failures: Option<u32>,
+    /// How long a target stays down before being tried again, in milliseconds, defaults to 10
+    /// seconds.
+    #[serde(default)]
+    pub down_time: Option<u64>,
+    /// Rewrite the path of the request (without the query string) with a regex and its
+    /// replacement, where `$1` or `${name}` refer to the capture groups.
     #[serde(default)]
     pub path_rewrite: Option<(String, String)>,
+    /// Remove the path of the proxy from the request, so `/api/users` is sent as `/users`.
+    /// Shorthand for `path_rewrite = ["^/api", ""]`.
+    #[serde(default)]
+    pub strip_prefix: bool,
+    /// Send the host of the target in the `Host` header instead of the one of the spa-server,
+    /// for backends behind virtual hosts or CDNs.
+    #[serde(default)]
+    pub change_origin: bool,
     /// Headers to add to the proxied request.
     #[serde(default)]
     pub headers: HashMap<String, String>,
+    /// The maximum time to connect to the target, in milliseconds.
+    #[serde(default)]
+    pub connect_timeout: Option<u64>,
+    /// The maximum time for the target to send the status and headers of the response, in
+    /// milliseconds. The body can then take as long as needed, so streaming still works.
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// How many times a request is sent again when the target can't be reached, e.g. while it
+    /// restarts. Only idempotent requests without body (`GET`, `HEAD`, `DELETE`...) are retried,
+    /// after 100ms, then twice longer each time, up to 5s.
+    #[serde(default)]
+    pub retries: u32,
+    /// The certificates of the authorities trusted for a https target, in PEM format, instead of
+    /// the ones of the system, e.g. for an internal CA. It can contain the `~` and environment
+    /// variables.
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// Accept any certificate from a https target, even self-signed or for another host.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// The certificate sent to targets requiring a client certificate (mutual TLS), in PEM
+    /// format. It can contain the `~` and environment variables.
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// The private key of `client_cert`, in PEM format. It can contain the `~` and environment
+    /// variables.
+    #[serde(default)]
+    pub client_key: Option<String>,
+    /// Proxies with a higher priority are matched first, whatever the specificity of their path.
+    #[serde(default)]
+    pub priority: i32,
+    /// Record the proxied traffic, or replay it from a previous recording.
+    #[serde(default)]
+    pub record: Option<RecordConfig>,
+    /// Inject latency and faults in the proxied requests.
+    #[serde(default)]
+    pub faults: Option<FaultConfig>,
+    /// Rewrite the cookies set by the target.
+    #[serde(default)]
+    pub cookies: Option<CookieConfig>,
+    /// Rewrite the `Location` and `Content-Location` headers pointing to the target, so redirects
+    /// stay on the spa-server.
+    #[serde(default)]
+    pub rewrite_location: bool,
+    /// Also proxy websockets: requests with `Upgrade: websocket` are forwarded, and the
+    /// connection is then piped to the target.
+    #[serde(default)]
+    pub ws: bool,
+    /// Requests matching the proxy path, but served by the application instead of the target.
+    #[serde(default)]
+    pub bypass: Option<BypassConfig>,
+}
+
+/// Let some requests matching a proxy fall through to the application, like the navigations of a
+/// browser to client-side routes. A request is bypassed as soon as one of the rules matches.
+/// # Example
+/// ```toml
+/// [proxies."/api"]
+/// target = "http://localhost:8080"
+/// # opening `/api/ui` in the browser shows the application, `fetch("/api/ui")` still goes to
+/// # the target
+/// bypass = { html = true, paths = ["/api/docs/**"], methods = ["OPTIONS"] }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BypassConfig {
+    /// Bypass the requests accepting HTML.
+    #[serde(default)]
+    pub html: bool,
+    /// Globs matched on the path of the request, `*` does not match `/` but `**` does.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Bypass the requests with one of these methods.
+    #[serde(default)]
+    pub methods: Vec<String>,
+}
+
+/// Latency and faults injected in the requests going through a proxy. They can be enabled or
+/// disabled at runtime with `POST /__spa-server/faults?enabled=false`, and optionally
+/// `&proxy=/api` to only target one proxy. Globs and regexes are given as they are written in the
+/// config, url-encoded.
+/// # Example
+/// ```toml
+/// [proxies."/api"]
+/// target = "http://localhost:8080"
+/// faults = { delay = 500, delay_max = 2000, error_rate = 10 }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct FaultConfig {
+    /// Whether the faults are injected at startup, defaults to `true`.
+    #[serde(default = "FaultConfig::default_enabled")]
+    pub enabled: bool,
+    /// A delay, in milliseconds, added before forwarding the request.
+    #[serde(default)]
+    pub delay: u64,
+    /// If set, the delay will be picked randomly between `delay` and `delay_max`.
+    #[serde(default)]
+    pub delay_max: Option<u64>,
+    /// The percentage of requests answered with `error_status` instead of being forwarded.
+    #[serde(default)]
+    pub error_rate: f64,
+    /// The status of the injected errors, defaults to `502`.
+    #[serde(default = "FaultConfig::default_error_status")]
+    pub error_status: u16,
+    /// The percentage of requests for which the connection is dropped without any response.
+    #[serde(default)]
+    pub reset_rate: f64,
+    /// Limit the bandwidth of the responses, in kilobytes per second.
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+}
+
+impl FaultConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+    fn default_error_status() -> u16 {
+        502
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    Random,
+    FirstHealthy,
+}
+
+/// Changes to the cookies set by a proxy target, so the browser keeps them for the spa-server.
+/// # Example
+/// ```toml
+/// [proxies."/api"]
+/// target = "http://api.internal/v1"
+/// strip_prefix = true
+/// # remove `Domain=api.internal`, and replace `Path=/v1` with `Path=/api`
+/// cookies = { domain = "", path = { "/v1" = "/api" }, secure = false }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CookieConfig {
+    /// Rewrite the `Domain` attribute.
+    #[serde(default)]
+    pub domain: Option<AttributeRewrite>,
+    /// Rewrite the `Path` attribute.
+    #[serde(default)]
+    pub path: Option<AttributeRewrite>,
+    /// Add the `Secure` attribute with `true`, or remove it with `false` so the cookies are kept
+    /// on plain http.
+    #[serde(default)]
+    pub secure: Option<bool>,
+    /// Set the `SameSite` attribute to `strict`, `lax` or `none`, or `remove` it.
+    #[serde(default)]
+    pub same_site: Option<SameSite>,
+}
+
+/// The new value of a cookie attribute: either one value for all the cookies, or a table from the
+/// original values to the new ones, where `*` matches any value. An empty value removes the
+/// attribute.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum AttributeRewrite {
+    All(String),
+    Map(HashMap<String, String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+    Remove,
+}
+
+/// Recording of the traffic going through a proxy.
+/// # Example
+/// ```toml
+/// [proxies."/api"]
+/// target = "http://localhost:8080"
+/// record = { mode = "replay", match_on = ["method", "path"] }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordConfig {
+    /// Either `record`, to forward the requests and store the responses, or `replay`, to only
+    /// serve the stored responses, without ever calling the target.
+    pub mode: RecordMode,
+    /// The parts of the request used to find a recorded response, defaults to
+    /// `["method", "path", "query"]`.
+    #[serde(default = "RecordConfig::default_match_on")]
+    pub match_on: Vec<RecordMatch>,
+    /// The folder where recordings are stored, defaults to a folder in the cache. It can contain
+    /// the `~` and environment variables.
+    #[serde(default)]
+    pub folder: Option<String>,
+}
+
+impl RecordConfig {
+    fn default_match_on() -> Vec<RecordMatch> {
+        vec![RecordMatch::Method, RecordMatch::Path, RecordMatch::Query]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordMode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordMatch {
+    Method,
+    Path,
+    Query,
+    Body,
+}
+
+/// A mocked route, answering with a fixed response.
+/// # Example
+/// ```toml
+/// [[mocks]]
+/// method = "GET"
+/// path = "/api/users/:id"
+/// json = { id = "{{id}}", name = "John Doe" }
+/// template = true
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct MockRoute {
+    /// The method to match, any method is matched if not set.
+    #[serde(default)]
+    pub method: Option<String>,
+    /// The path to match. Segments starting with `:` match any segment and capture it under that
+    /// name, and a trailing `*` matches the rest of the path.
+    pub path: String,
+    /// The status code of the response, defaults to `200`.
+    #[serde(default = "MockRoute::default_status")]
+    pub status: u16,
+    /// Headers to add to the response.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// An inline body, sent as `text/plain` unless a `content-type` header is set.
+    #[serde(default)]
+    pub body: Option<String>,
+    /// An inline body, converted to JSON and sent as `application/json`.
+    #[serde(default)]
+    pub json: Option<toml::Value>,
+    /// A file to read the body from, on each request. Its mime type is guessed from its
+    /// extension. It can contain the `~` and environment variables.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Replace `{{name}}` in the body by the segment captured by `:name` in the path. The values
+    /// are escaped in json bodies, so they can be put in strings.
+    #[serde(default)]
+    pub template: bool,
+}
+
+impl MockRoute {
+    fn default_status() -> u16 {
+        200
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThrottleRule {
+    /// A glob matched on the path of the request, `*` does not match `/` but `**` does.
+    pub path: String,
+    /// The network profile to apply.
+    pub profile: NetworkProfile,
+}
+
+/// Simulate a slow network, with a bandwidth in kilobits per second, and a latency in
+/// milliseconds added before the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum NetworkProfile {
+    Named(NamedProfile),
+    Custom {
+        kbps: u64,
+        #[serde(default)]
+        latency: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum NamedProfile {
+    /// Same values as the browsers' devtools: 400kbps, 2000ms.
+    #[serde(rename = "slow-3g")]
+    Slow3g,
+    /// Same values as the browsers' devtools: 1475kbps, 563ms.
+    #[serde(rename = "fast-3g")]
+    Fast3g,
+}
+
+impl NetworkProfile {
+    pub fn kbps(&self) -> u64 {
+        match self {
+            NetworkProfile::Named(NamedProfile::Slow3g) => 400,
+            NetworkProfile::Named(NamedProfile::Fast3g) => 1475,
+            NetworkProfile::Custom { kbps, .. } => *kbps,
+        }
+    }
+    pub fn latency(&self) -> u64 {
+        match self {
+            NetworkProfile::Named(NamedProfile::Slow3g) => 2000,
+            NetworkProfile::Named(NamedProfile::Fast3g) => 563,
+            NetworkProfile::Custom { latency, .. } => *latency,
+        }
+    }
+}
+
+/// Parse a profile from the cli: `slow-3g`, `fast-3g`, or `<kbps>/<latency>` for a custom one.
+impl FromStr for NetworkProfile {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "slow-3g" => Ok(NetworkProfile::Named(NamedProfile::Slow3g)),
+            "fast-3g" => Ok(NetworkProfile::Named(NamedProfile::Fast3g)),
+            custom => {
+                let mut parts = custom.splitn(2, '/');
+                let kbps = parts.next().unwrap_or_default();
+                let kbps = kbps
+                    .parse()
+                    .map_err(|_| format!("invalid network profile: `{}`", custom))?;
+                let latency = parts
+                    .next()
+                    .map(str::parse)
+                    .transpose()
+                    .map_err(|_| format!("invalid network profile: `{}`", custom))?
+                    .unwrap_or(0);
+                Ok(NetworkProfile::Custom { kbps, latency })
+            }
+        }
+    }
 }
 
 pub fn from_folder(folder: String) -> Config {
@@ -66,8 +565,20 @@ pub fn from_folder(folder: String) -> Config {
             base_path: None,
             host: ServerConfig::default_host(),
             port: ServerConfig::default_port(),
+            listen: Vec::new(),
+            pool_size: None,
+            max_connections: None,
+            max_header_size: None,
+            max_body_size: None,
+            idle_timeout: None,
+            port_fallback: false,
+            port_file: None,
+            throttle: None,
+            start_path: None,
         },
         proxies: HashMap::new(),
+        mocks: Vec::new(),
+        throttles: Vec::new(),
     }
 }
 
@@ -96,7 +607,7 @@ impl ConfigPath {
                     )
                 })?
             }
-            ConfigPath::Provided(path) => fs::read_to_string(&path)
+            ConfigPath::Provided(path) => fs::read_to_string(path)
                 .with_context(|| format!("could not read config file at {}", path))?,
         };
         toml::from_str::<Config>(&config)
@@ -112,3 +623,78 @@ impl fmt::Display for ConfigPath {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_profile_from_str() {
+        assert_eq!(
+            "slow-3g".parse(),
+            Ok(NetworkProfile::Named(NamedProfile::Slow3g))
+        );
+        assert_eq!(
+            "fast-3g".parse(),
+            Ok(NetworkProfile::Named(NamedProfile::Fast3g))
+        );
+        assert_eq!(
+            "750/100".parse(),
+            Ok(NetworkProfile::Custom {
+                kbps: 750,
+                latency: 100
+            })
+        );
+        assert_eq!(
+            "750".parse(),
+            Ok(NetworkProfile::Custom {
+                kbps: 750,
+                latency: 0
+            })
+        );
+        assert!("slow-4g".parse::<NetworkProfile>().is_err());
+        assert!("750/fast".parse::<NetworkProfile>().is_err());
+    }
+
+    #[test]
+    fn port_deserialize() {
+        let server = |port: &str| {
+            toml::from_str::<ServerConfig>(&format!("serve = \"dist\"\n{}", port))
+                .map(|server| server.port)
+        };
+        assert_eq!(server("").unwrap(), 4242);
+        assert_eq!(server("port = 8080").unwrap(), 8080);
+        assert_eq!(server("port = \"auto\"").unwrap(), 0);
+        assert!(server("port = \"other\"").is_err());
+        assert!(server("port = 100000").is_err());
+    }
+
+    #[test]
+    fn limits_deserialize() {
+        let server =
+            |limits: &str| toml::from_str::<ServerConfig>(&format!("serve = \"dist\"\n{}", limits));
+        let config = server("pool_size = 2\nmax_connections = 100").unwrap();
+        assert_eq!(config.pool_size.map(NonZeroUsize::get), Some(2));
+        assert_eq!(config.max_connections.map(NonZeroUsize::get), Some(100));
+        assert!(server("pool_size = 0").is_err());
+        assert!(server("max_connections = 0").is_err());
+    }
+
+    #[test]
+    fn network_profile_deserialize() {
+        #[derive(Deserialize)]
+        struct Wrapper {
+            profile: NetworkProfile,
+        }
+        let w: Wrapper = toml::from_str(r#"profile = "slow-3g""#).unwrap();
+        assert_eq!(w.profile, NetworkProfile::Named(NamedProfile::Slow3g));
+        let w: Wrapper = toml::from_str(r#"profile = { kbps = 750, latency = 100 }"#).unwrap();
+        assert_eq!(
+            w.profile,
+            NetworkProfile::Custom {
+                kbps: 750,
+                latency: 100
+            }
+        );
+    }
+}
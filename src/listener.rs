@@ -0,0 +1,789 @@
+use std::{
+    convert::Infallible,
+    env,
+    future::Future,
+    io,
+    net::{SocketAddr, TcpListener},
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header,
+    server::conn::Http,
+    service::service_fn,
+    Body, HeaderMap, StatusCode,
+};
+#[cfg(feature = "tls")]
+use openssl::{
+    pkey::PKey,
+    ssl::{Ssl, SslAcceptor, SslMethod},
+    x509::X509,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+    addresses,
+    config::{ListenConfig, ServerConfig, TlsConfig},
+    server::{Peer, Request, Response},
+    tls::Acceptor,
+};
+
+/// Prefix of the `host` to listen on a unix domain socket, like `unix:/run/spa-server.sock`.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// How long a client has to send the headers of a request when `max_connections` is set, so
+/// connections sending nothing don't keep their slot forever.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A bound socket, ready to accept connections.
+pub struct Listener {
+    socket: Socket,
+    tls: Option<Acceptor>,
+    activated: bool,
+    idle_timeout: Option<Duration>,
+    header_timeout: Option<Duration>,
+    connections: Arc<Connections>,
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, String),
+}
+
+/// Bind a socket for each configured listener. If sockets are passed by systemd, they are used
+/// instead of the configured listeners.
+pub fn bind_all(config: &ServerConfig) -> Result<Vec<Listener>> {
+    let idle_timeout = config.idle_timeout.map(Duration::from_secs);
+    let header_timeout = config.max_connections.map(|_| HEADER_TIMEOUT);
+    // shared by all the listeners
+    let connections = Arc::new(Connections::new(
+        config.max_connections.map(NonZeroUsize::get),
+    ));
+    if let Some(sockets) = inherited()? {
+        return Ok(sockets
+            .into_iter()
+            .map(|socket| Listener {
+                socket,
+                tls: None,
+                activated: true,
+                idle_timeout,
+                header_timeout,
+                connections: connections.clone(),
+            })
+            .collect());
+    }
+    config
+        .listeners()
+        .iter()
+        .map(|listen| {
+            let tls = listen.tls.as_ref().map(load_tls).transpose()?;
+            let socket = bind(listen, config.port_fallback)?;
+            Ok(Listener {
+                socket,
+                tls,
+                activated: false,
+                idle_timeout,
+                header_timeout,
+                connections: connections.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Listen on the configured host and port, trying the next ports if `port_fallback` is set.
+fn bind(config: &ListenConfig, port_fallback: bool) -> Result<Socket> {
+    if let Some(path) = config.host.strip_prefix(UNIX_PREFIX) {
+        return unix_socket(path);
+    }
+    let host = config.host.as_str();
+    let mut ports = vec![config.port];
+    if port_fallback && config.port != 0 {
+        ports.extend(
+            (1..ServerConfig::PORT_FALLBACK_ATTEMPTS)
+                .filter_map(|offset| config.port.checked_add(offset)),
+        );
+        ports.push(0);
+    }
+    let mut last_error = None;
+    for port in ports {
+        match TcpListener::bind((host, port)) {
+            Ok(listener) => {
+                if port != config.port {
+                    warn!(
+                        "port {} is not available, using port {} instead",
+                        config.port,
+                        listener.local_addr()?.port()
+                    );
+                }
+                return Ok(Socket::Tcp(listener));
+            }
+            Err(e) => {
+                debug!("failed to listen on {}:{}: {}", host, port, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("at least one port is tried"))
+        .with_context(|| format!("Failed to listen on {}:{}", host, config.port))
+}
+
+#[cfg(feature = "tls")]
+fn load_tls(config: &TlsConfig) -> Result<Acceptor> {
+    let read = |path: &str| {
+        let path =
+            shellexpand::full(path).with_context(|| format!("failed to expand path: {}", path))?;
+        std::fs::read(path.as_ref()).with_context(|| format!("failed to read `{}`", path))
+    };
+    let certs = X509::stack_from_pem(&read(&config.cert)?)
+        .with_context(|| format!("invalid certificate: {}", config.cert))?;
+    let key = PKey::private_key_from_pem(&read(&config.key)?)
+        .with_context(|| format!("invalid private key: {}", config.key))?;
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    let mut certs = certs.into_iter();
+    let cert = certs
+        .next()
+        .with_context(|| format!("no certificate found in {}", config.cert))?;
+    acceptor.set_certificate(&cert)?;
+    for cert in certs {
+        acceptor.add_extra_chain_cert(cert)?;
+    }
+    acceptor.set_private_key(&key)?;
+    acceptor
+        .check_private_key()
+        .with_context(|| format!("`{}` is not the key of `{}`", config.key, config.cert))?;
+    Ok(acceptor.build())
+}
+
+#[cfg(not(feature = "tls"))]
+fn load_tls(config: &TlsConfig) -> Result<Acceptor> {
+    anyhow::bail!(
+        "TLS is not supported by this build, enable the `tls` feature to use `{}` and `{}`",
+        config.cert,
+        config.key
+    )
+}
+
+impl Listener {
+    /// The address of the TCP socket, if the server is reached through one.
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Socket::Unix(..) => None,
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Human readable description of where the server can be reached.
+    pub fn describe(&self, start_path: &str) -> Vec<String> {
+        match (&self.socket, self.tcp_addr()) {
+            (Socket::Tcp(_), Some(addr)) if self.activated => {
+                vec![format!("{} (socket activation)", addr)]
+            }
+            (Socket::Tcp(_), Some(addr)) => addresses::reachable_addrs(addr)
+                .into_iter()
+                .map(|addr| addresses::url(self.scheme(), addr, start_path))
+                .collect(),
+            (Socket::Tcp(_), None) => vec!["unknown address".to_owned()],
+            #[cfg(unix)]
+            (Socket::Unix(_, path), _) => vec![format!("{}{}", UNIX_PREFIX, path)],
+        }
+    }
+
+    /// Accept the connections, each one served in its own task.
+    pub async fn run<F, Fut>(self, handler: F) -> Result<()>
+    where
+        F: Fn(Request) -> Fut + Clone + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let connection = Connection {
+            tls: self.tls.map(Arc::new),
+            idle_timeout: self.idle_timeout,
+            header_timeout: self.header_timeout,
+            connections: self.connections,
+        };
+        match self.socket {
+            Socket::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, addr)) => {
+                            stream.set_nodelay(true).ok();
+                            connection.spawn(stream, Some(addr), handler.clone());
+                        }
+                        Err(e) => warn!("failed to accept connection: {}", e),
+                    }
+                }
+            }
+            #[cfg(unix)]
+            Socket::Unix(listener, _) => {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => connection.spawn(stream, None, handler.clone()),
+                        Err(e) => warn!("failed to accept connection: {}", e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How the accepted connections are served.
+struct Connection {
+    tls: Option<Arc<Acceptor>>,
+    idle_timeout: Option<Duration>,
+    header_timeout: Option<Duration>,
+    connections: Arc<Connections>,
+}
+
+impl Connection {
+    fn spawn<S, F, Fut>(&self, stream: S, addr: Option<SocketAddr>, handler: F)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Fn(Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response> + Send + 'static,
+    {
+        let tls = self.tls.clone();
+        let idle_timeout = self.idle_timeout;
+        let header_timeout = self.header_timeout;
+        let peer = Peer {
+            addr,
+            tls: tls.is_some(),
+        };
+        // the slot is kept by the stream, so until the connection is closed, even once upgraded
+        let (slot, rejected) = self.connections.open();
+        tokio::spawn(async move {
+            let activity = Arc::new(Activity::new());
+            let stream = Tracked {
+                inner: stream,
+                activity: activity.clone(),
+                _slot: slot,
+            };
+            let timeouts = (idle_timeout, header_timeout);
+            let served = match tls {
+                Some(acceptor) => match accept_tls(&acceptor, stream).await {
+                    Ok(stream) => serve(stream, peer, handler, activity, timeouts, rejected).await,
+                    Err(e) => {
+                        debug!("failed TLS handshake: {:#}", e);
+                        return;
+                    }
+                },
+                None => serve(stream, peer, handler, activity, timeouts, rejected).await,
+            };
+            if let Err(e) = served {
+                debug!("failed to serve connection: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "tls")]
+async fn accept_tls<S>(acceptor: &Acceptor, stream: S) -> Result<tokio_openssl::SslStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = tokio_openssl::SslStream::new(ssl, stream)?;
+    Pin::new(&mut stream).accept().await?;
+    Ok(stream)
+}
+
+#[cfg(not(feature = "tls"))]
+async fn accept_tls<S>(acceptor: &Acceptor, _stream: S) -> Result<S> {
+    match *acceptor {}
+}
+
+async fn serve<S, F, Fut>(
+    stream: S,
+    peer: Peer,
+    handler: F,
+    activity: Arc<Activity>,
+    (idle_timeout, header_timeout): (Option<Duration>, Option<Duration>),
+    rejected: bool,
+) -> hyper::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    let pending = activity.clone();
+    let service = service_fn(move |mut request: Request| {
+        request.extensions_mut().insert(peer);
+        let busy = pending.busy();
+        let response = if rejected {
+            warn!("too many connections, rejecting {}", request.uri());
+            None
+        } else {
+            Some(handler(request))
+        };
+        async move {
+            let response = match response {
+                Some(response) => response.await,
+                None => service_unavailable(),
+            };
+            let response = response.map(|body| BusyBody { body, _busy: busy });
+            Ok::<_, Infallible>(response)
+        }
+    });
+    let mut http = Http::new();
+    http.http1_keep_alive(!rejected);
+    if let Some(timeout) = header_timeout {
+        http.http1_header_read_timeout(timeout);
+    }
+    let connection = http.serve_connection(stream, service).with_upgrades();
+    match idle_timeout {
+        Some(timeout) => tokio::select! {
+            served = connection => served,
+            _ = activity.idle_for(timeout) => {
+                debug!("closing idle connection");
+                Ok(())
+            }
+        },
+        None => connection.await,
+    }
+}
+
+/// The response to the requests of a connection over `max_connections`, which is then closed.
+fn service_unavailable() -> Response {
+    let mut response = Response::new("Service Unavailable".into());
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert(header::RETRY_AFTER, header::HeaderValue::from_static("1"));
+    response
+}
+
+/// The connections open on all the listeners, at most `max_connections`.
+struct Connections {
+    max: Option<usize>,
+    open: AtomicUsize,
+}
+
+impl Connections {
+    fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a new connection, which must be rejected if `max_connections` are already open.
+    fn open(self: &Arc<Self>) -> (Slot, bool) {
+        let count = self.open.fetch_add(1, Ordering::SeqCst);
+        let rejected = matches!(self.max, Some(max) if count >= max);
+        (Slot(self.clone()), rejected)
+    }
+}
+
+/// Releases the slot of a connection once it is closed.
+struct Slot(Arc<Connections>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The last time something was sent or received on a connection, and whether a response is
+/// still pending. A connection is only idle between requests: a slow handler or a quiet streamed
+/// body must not be cut.
+struct Activity {
+    start: Instant,
+    last: AtomicU64,
+    pending: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    /// Mark the connection busy until the response to a request is sent.
+    fn busy(self: &Arc<Self>) -> Busy {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        Busy(self.clone())
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing happened for `timeout`, while no response was pending.
+    async fn idle_for(&self, timeout: Duration) {
+        loop {
+            if self.pending.load(Ordering::Relaxed) > 0 {
+                tokio::time::sleep(timeout).await;
+                continue;
+            }
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let idle = last.elapsed();
+            if idle >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    }
+}
+
+/// Releases a pending response, the connection is idle from now on if it was the last one.
+struct Busy(Arc<Activity>);
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A response body keeping the connection busy until it is sent, or dropped.
+struct BusyBody {
+    body: Body,
+    _busy: Busy,
+}
+
+impl HttpBody for BusyBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// A stream recording its activity, and holding its slot in the open connections.
+struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+    _slot: Slot,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if poll.is_ready() {
+            self.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(unix)]
+fn unix_socket(path: &str) -> Result<Socket> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+    let path = shellexpand::full(path)
+        .with_context(|| format!("failed to expand path: {}", path))?
+        .into_owned();
+    // remove the socket left by a previous run, but never a regular file
+    if let Ok(metadata) = std::fs::metadata(&path) {
+        anyhow::ensure!(
+            metadata.file_type().is_socket(),
+            "`{}` already exists and is not a socket",
+            path
+        );
+        std::fs::remove_file(&path)
+            .with_context(|| format!("failed to remove old socket: {}", path))?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to listen on unix socket {}", path))?;
+    Ok(Socket::Unix(listener, path))
+}
+
+#[cfg(not(unix))]
+fn unix_socket(path: &str) -> Result<Socket> {
+    anyhow::bail!("unix sockets are not supported on this platform: {}", path)
+}
+
+/// The sockets passed by systemd, following `sd_listen_fds(3)`.
+#[cfg(unix)]
+fn inherited() -> Result<Option<Vec<Socket>>> {
+    use std::os::unix::{
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::UnixListener,
+    };
+    const SD_LISTEN_FDS_START: RawFd = 3;
+
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let fds = match (pid, fds) {
+        (Some(pid), Some(fds)) if pid == std::process::id().to_string() => fds,
+        _ => return Ok(None),
+    };
+    let fds = fds
+        .parse::<RawFd>()
+        .with_context(|| format!("invalid LISTEN_FDS: {}", fds))?;
+    if fds <= 0 {
+        return Ok(None);
+    }
+    debug!("inherited {} socket(s) from systemd", fds);
+    let sockets = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .map(|fd| {
+            // safety: systemd guarantees these file descriptors are open listening sockets,
+            // and we are the only ones using them since the environment variables are removed
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            if listener.local_addr().is_ok() {
+                Socket::Tcp(listener)
+            } else {
+                let fd = listener.into_raw_fd();
+                let listener = unsafe { UnixListener::from_raw_fd(fd) };
+                let path = listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(|p| p.display().to_string()))
+                    .unwrap_or_else(|| format!("fd {}", fd));
+                Socket::Unix(listener, path)
+            }
+        })
+        .collect();
+    Ok(Some(sockets))
+}
+
+#[cfg(not(unix))]
+fn inherited() -> Result<Option<Vec<Socket>>> {
+    Ok(None)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn listener(socket: Socket, idle_timeout: Option<Duration>) -> Listener {
+        Listener {
+            socket,
+            tls: None,
+            activated: false,
+            idle_timeout,
+            header_timeout: None,
+            connections: Arc::new(Connections::new(None)),
+        }
+    }
+
+    async fn hello(request: Request) -> Response {
+        Response::new(format!("hello {}", request.uri()).into())
+    }
+
+    #[tokio::test]
+    async fn unix_socket_serving() {
+        let path = env::temp_dir().join("spa-server-test-serving.sock");
+        let path = path.to_str().unwrap().to_owned();
+        let socket = unix_socket(&path).unwrap();
+        tokio::spawn(listener(socket, None).run(hello));
+        let response = tokio::task::spawn_blocking(move || {
+            let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            std::fs::remove_file(path).ok();
+            response
+        })
+        .await
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("hello /"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn max_connections() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = Listener {
+            connections: Arc::new(Connections::new(Some(1))),
+            ..listener(Socket::Tcp(socket), None)
+        };
+        tokio::spawn(socket.run(hello));
+        let (second, third) = tokio::task::spawn_blocking(move || {
+            let request = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            let get = |client: &mut std::net::TcpStream| {
+                client.write_all(request).unwrap();
+                let mut response = Vec::new();
+                let mut buffer = [0; 256];
+                while !response.ends_with(b"hello /") && !response.ends_with(b"Unavailable") {
+                    let read = client.read(&mut buffer).unwrap();
+                    assert_ne!(read, 0);
+                    response.extend_from_slice(&buffer[..read]);
+                }
+                String::from_utf8(response).unwrap()
+            };
+            // kept open between requests
+            let mut first = std::net::TcpStream::connect(addr).unwrap();
+            assert!(get(&mut first).starts_with("HTTP/1.1 200 OK"));
+            let mut second = std::net::TcpStream::connect(addr).unwrap();
+            let second_response = get(&mut second);
+            assert_eq!(second.read(&mut [0; 16]).unwrap(), 0);
+            assert!(get(&mut first).starts_with("HTTP/1.1 200 OK"));
+            drop(first);
+            std::thread::sleep(Duration::from_millis(100));
+            let mut third = std::net::TcpStream::connect(addr).unwrap();
+            (second_response, get(&mut third))
+        })
+        .await
+        .unwrap();
+        assert!(second.starts_with("HTTP/1.1 503"), "{}", second);
+        assert!(second.contains("retry-after: 1"), "{}", second);
+        assert!(third.starts_with("HTTP/1.1 200 OK"), "{}", third);
+    }
+
+    #[tokio::test]
+    async fn header_timeout() {
+        async fn slow(request: Request) -> Response {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            hello(request).await
+        }
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = Listener {
+            header_timeout: Some(Duration::from_millis(200)),
+            ..listener(Socket::Tcp(socket), None)
+        };
+        tokio::spawn(socket.run(slow));
+        let (silent, response) = tokio::task::spawn_blocking(move || {
+            // closed without sending anything
+            let start = Instant::now();
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+            let silent = start.elapsed();
+            // a slow response is not cut
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            (silent, response)
+        })
+        .await
+        .unwrap();
+        assert!(silent >= Duration::from_millis(200), "{:?}", silent);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("hello /"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = listener(Socket::Tcp(socket), Some(Duration::from_millis(200)));
+        tokio::spawn(socket.run(hello));
+        let (read, elapsed) = tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            let read = client.read(&mut [0; 16]).unwrap();
+            (read, start.elapsed())
+        })
+        .await
+        .unwrap();
+        assert_eq!(read, 0);
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_pending_response() {
+        // slower than the timeout to answer, then to send the end of the body
+        async fn slow(_: Request) -> Response {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                sender.send_data("hello ".into()).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                sender.send_data("slow".into()).await.unwrap();
+            });
+            Response::new(body)
+        }
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = listener(Socket::Tcp(socket), Some(Duration::from_millis(200)));
+        tokio::spawn(socket.run(slow));
+        let response = tokio::task::spawn_blocking(move || {
+            let mut client = std::net::TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            // the connection is then closed as idle
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        })
+        .await
+        .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("hello "), "{}", response);
+        assert!(response.contains("slow"), "{}", response);
+    }
+}
@@ -6,13 +6,17 @@ use anyhow::{Context, Result};
 use argh::FromArgs;
 use log::LevelFilter;
 
-use config::ConfigPath;
+use config::{ConfigPath, NetworkProfile};
 use server::Server;
 
+mod addresses;
 mod cache;
 mod config;
+mod listener;
+mod provider;
 mod server;
 mod source;
+mod tls;
 
 #[derive(Debug, FromArgs)]
 /// spa-server, a local server for already built SPAs (Single Page Applications).
@@ -23,6 +27,21 @@ struct Options {
     /// serve from a folder instead of reading from config
     #[argh(option, short = 's')]
     serve: Option<String>,
+    /// network profile applied to static files: `slow-3g`, `fast-3g`, or `<kbps>/<latency>`
+    #[argh(option)]
+    throttle: Option<NetworkProfile>,
+    /// open the application in the default browser
+    #[argh(switch, short = 'o')]
+    open: bool,
+    /// print a QR code of the application url, to open it on a phone
+    #[argh(switch)]
+    qr: bool,
+    /// if the port is already in use, try the next ones
+    #[argh(switch)]
+    port_fallback: bool,
+    /// write the port actually used to this file
+    #[argh(option)]
+    port_file: Option<String>,
     /// optional `dotenv` file with variables needed for path url
     #[argh(option, short = 'e')]
     env_file: Option<String>,
@@ -64,10 +83,10 @@ fn setup_logger(level: LevelFilter) -> Result<()> {
 }
 
 fn main() -> Result<()> {
-    let opts: Options = argh::from_env();
+    let mut opts: Options = argh::from_env();
     setup_logger(opts.log).context("failed to init logger, this is surely a bug")?;
     trace!("options: {:#?}", opts);
-    let config = if let Some(folder) = &opts.serve {
+    let mut config = if let Some(folder) = &opts.serve {
         trace!("using serve option instead of config file");
         config::from_folder(folder.to_owned())
     } else {
@@ -77,36 +96,89 @@ fn main() -> Result<()> {
             .unwrap_or(ConfigPath::Default);
         config_location.read()?
     };
+    if let Some(throttle) = opts.throttle {
+        config.server.throttle = Some(throttle);
+    }
+    if opts.port_fallback {
+        config.server.port_fallback = true;
+    }
+    if let Some(port_file) = opts.port_file.take() {
+        config.server.port_file = Some(port_file);
+    }
 
     load_env_file(opts.env_file.as_deref())?;
 
     let app_path = expand_path(&config.server.serve)?;
     let source = source::detect(&app_path);
     let cache = cache::Cache::init()?;
-    let folder = source.setup(&cache, config.server.base_path.as_deref())?;
+    let files = source.setup(&cache, config.server.base_path.as_deref())?;
 
-    debug!("serving from: {}", folder.display());
+    debug!("serving from: {:?}", files);
 
-    let server = Server::new(folder, &config.proxies)?;
+    let server = Server::new(files, &config, &cache)?;
     debug!("proxies: {:?}", server.proxies);
-
-    let addr = (config.server.host.as_ref(), config.server.port);
-
-    let server = rouille::Server::new(addr, move |request| {
-        rouille::log_custom(request, server::log_success, server::log_error, || {
-            server.serve_request(request)
-        })
+    debug!("mocks: {:?}", server.mocks);
+
+    let listeners = listener::bind_all(&config.server)?;
+
+    let start_path = config.server.start_path.as_deref().unwrap_or("/");
+    for listener in listeners.iter() {
+        for url in listener.describe(start_path) {
+            println!("Listening on {}", url);
+        }
+    }
+    let first_tcp = listeners
+        .iter()
+        .find_map(|listener| listener.tcp_addr().map(|addr| (listener.scheme(), addr)));
+
+    if let (Some(port_file), Some((_, addr))) = (&config.server.port_file, first_tcp) {
+        let port_file = expand_path(port_file)?;
+        std::fs::write(port_file.as_ref(), addr.port().to_string())
+            .with_context(|| format!("failed to write port to `{}`", port_file))?;
+    }
+    if let (true, Some((scheme, addr))) = (opts.qr, first_tcp) {
+        let addrs = addresses::reachable_addrs(addr);
+        match addrs
+            .iter()
+            .find(|addr| !addr.ip().is_loopback())
+            .or_else(|| addrs.first())
+        {
+            Some(addr) => println!(
+                "{}",
+                addresses::qr_code(&addresses::url(scheme, *addr, start_path))?
+            ),
+            None => warn!("no reachable address found for the QR code"),
+        }
+    }
+    if let (true, Some((scheme, addr))) = (opts.open, first_tcp) {
+        let url = addresses::browser_url(scheme, addr, start_path);
+        if let Err(e) = addresses::open_browser(&url) {
+            warn!("{:#}", e);
+        }
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(pool_size) = config.server.pool_size {
+        runtime.worker_threads(pool_size.get());
+    }
+    let runtime = runtime
+        .build()
+        .context("failed to start the async runtime")?;
+    runtime.block_on(async move {
+        let handler = move |request| server::handle(server.clone(), request);
+        let runs = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(listener.run(handler.clone())))
+            .collect::<Vec<_>>();
+        for run in runs {
+            run.await.context("listener stopped unexpectedly")??;
+        }
+        Ok(())
     })
-    .map_err(|e| anyhow::anyhow!(e))
-    .with_context(|| format!("Failed to listen on port {}", addr.1))?
-    .pool_size(8 * num_cpus::get());
-
-    println!("Listening on http://{}", server.server_addr());
-    server.run();
-    Ok(())
 }
 
-fn expand_path(path: &str) -> Result<Cow<str>> {
+fn expand_path(path: &str) -> Result<Cow<'_, str>> {
     shellexpand::full(path).with_context(|| format!("failed to expand path: {}", path))
 }
 
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use hyper::body::{Body, Bytes};
+use tokio_util::io::ReaderStream;
+
+/// Gives access to the files of the application, wherever they are stored.
+pub trait FileProvider: fmt::Debug + Send + Sync {
+    /// Open the file at `path`, relative to the root of the application. Returns `None` if the
+    /// file does not exist.
+    fn open(&self, path: &Path) -> Option<FileContent>;
+}
+
+/// The content of a file, streamed, along with its size.
+pub struct FileContent {
+    pub body: Body,
+    pub len: u64,
+}
+
+/// Serve the files from a folder on disk.
+#[derive(Debug)]
+pub struct FolderProvider {
+    pub folder: PathBuf,
+}
+
+impl FolderProvider {
+    pub fn new(folder: PathBuf) -> Result<Self> {
+        let metadata = fs::metadata(&folder)
+            .with_context(|| format!("folder `{}` not found", folder.display()))?;
+        anyhow::ensure!(metadata.is_dir(), "`{}` is not a folder", folder.display());
+        Ok(Self { folder })
+    }
+}
+
+impl FileProvider for FolderProvider {
+    fn open(&self, path: &Path) -> Option<FileContent> {
+        // the path comes from the url, it must not escape the folder
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return None;
+        }
+        let file = fs::File::open(self.folder.join(path)).ok()?;
+        let metadata = file.metadata().ok()?;
+        if metadata.is_dir() {
+            return None;
+        }
+        let file = tokio::fs::File::from_std(file);
+        Some(FileContent {
+            body: Body::wrap_stream(ReaderStream::new(file)),
+            len: metadata.len(),
+        })
+    }
+}
+
+/// Serve the files from memory, used for archives so they don't need to be extracted.
+pub struct MemoryProvider {
+    name: String,
+    files: HashMap<PathBuf, Bytes>,
+}
+
+impl MemoryProvider {
+    /// Load all the files of a tar archive, only keeping the ones under `base_folder` if set.
+    pub fn from_tar<R: Read>(name: String, reader: R, base_folder: Option<&str>) -> Result<Self> {
+        let prefix = base_folder.map(normalize).unwrap_or_default();
+        let mut archive = tar::Archive::new(reader);
+        let mut files = HashMap::new();
+        for entry in archive.entries().context("failed to read archive")? {
+            let mut entry = entry.context("failed to read archive entry")?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let path = normalize(&entry.path().context("invalid path in archive")?);
+            let path = match path.strip_prefix(&prefix) {
+                Ok(path) => path.to_owned(),
+                Err(_) => continue,
+            };
+            let mut content = Vec::with_capacity(entry.header().size().unwrap_or(0) as usize);
+            entry
+                .read_to_end(&mut content)
+                .with_context(|| format!("failed to read `{}` in archive", path.display()))?;
+            trace!("loaded {} ({} bytes)", path.display(), content.len());
+            files.insert(path, Bytes::from(content));
+        }
+        // like a missing folder, rather than answering 404 to every request
+        anyhow::ensure!(
+            !files.is_empty(),
+            "no file found in archive {}{}",
+            name,
+            base_folder
+                .map(|base| format!(" under `{}`", base))
+                .unwrap_or_default()
+        );
+        debug!("loaded {} files from {}", files.len(), name);
+        Ok(Self { name, files })
+    }
+}
+
+impl fmt::Debug for MemoryProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryProvider")
+            .field("name", &self.name)
+            .field("files", &self.files.len())
+            .finish()
+    }
+}
+
+impl FileProvider for MemoryProvider {
+    fn open(&self, path: &Path) -> Option<FileContent> {
+        self.files.get(&normalize(path)).map(|content| FileContent {
+            body: Body::from(content.clone()),
+            len: content.len() as u64,
+        })
+    }
+}
+
+/// Only keep the normal components of a path, so `./app/index.html` and `app/index.html` are
+/// the same file.
+fn normalize(path: impl AsRef<Path>) -> PathBuf {
+    path.as_ref()
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn read(content: FileContent) -> String {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let data = runtime
+            .block_on(hyper::body::to_bytes(content.body))
+            .unwrap();
+        assert_eq!(data.len() as u64, content.len);
+        String::from_utf8(data.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn memory_provider_from_tar() {
+        let data = archive(&[
+            ("./index.html", "<html></html>"),
+            ("assets/main.js", "console.log()"),
+        ]);
+        let provider = MemoryProvider::from_tar("test".to_owned(), &data[..], None).unwrap();
+        assert_eq!(
+            read(provider.open(Path::new("index.html")).unwrap()),
+            "<html></html>"
+        );
+        assert_eq!(
+            read(provider.open(Path::new("assets/main.js")).unwrap()),
+            "console.log()"
+        );
+        assert!(provider.open(Path::new("assets")).is_none());
+        assert!(provider.open(Path::new("missing.js")).is_none());
+    }
+
+    #[test]
+    fn memory_provider_base_folder() {
+        let data = archive(&[
+            ("app/index.html", "<html></html>"),
+            ("other/index.html", "other"),
+        ]);
+        let provider =
+            MemoryProvider::from_tar("test".to_owned(), &data[..], Some("./app/")).unwrap();
+        assert_eq!(
+            read(provider.open(Path::new("index.html")).unwrap()),
+            "<html></html>"
+        );
+        assert!(provider.open(Path::new("other/index.html")).is_none());
+
+        let error = MemoryProvider::from_tar("test.tar".to_owned(), &data[..], Some("dist"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "no file found in archive test.tar under `dist`");
+        let empty = archive(&[]);
+        assert!(MemoryProvider::from_tar("test".to_owned(), &empty[..], None).is_err());
+    }
+}
@@ -1,17 +1,51 @@
-use std::{borrow::Cow, collections::HashMap, fs, path::PathBuf, sync::Arc};
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
-use crate::config::ProxyTarget;
+use crate::{
+    cache::Cache,
+    config::{Config, NetworkProfile},
+    provider::FileProvider,
+};
+use mock::MockConfig;
 use proxy::ProxyConfig;
 
 use anyhow::{Context, Result};
+use hyper::{body::HttpBody as _, header, StatusCode};
 use mime_guess::mime;
 use std::time::Duration;
 
+mod admin;
+mod cookie;
+mod fault;
+mod mock;
 mod proxy;
+mod record;
+mod response;
+mod throttle;
+#[cfg(unix)]
+mod unix;
+mod upload;
+mod upstream;
+mod websocket;
 
-pub fn log_success(request: &rouille::Request, _response: &rouille::Response, duration: Duration) {
-    let method = request.method();
-    let path = request.raw_url().split('?').next().unwrap();
+pub type Request = hyper::Request<hyper::Body>;
+pub type Response = hyper::Response<hyper::Body>;
+
+/// The client sending a request, added by the listener to the extensions of the request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Peer {
+    /// The address of the client, `None` on unix sockets.
+    pub addr: Option<SocketAddr>,
+    /// Whether the connection uses TLS.
+    pub tls: bool,
+}
+
+pub fn log_success(method: &str, path: &str, duration: Duration) {
     let time = duration.as_millis();
     debug!(
         "{method} {path} - {time}ms",
@@ -21,9 +55,7 @@ pub fn log_success(request: &rouille::Request, _response: &rouille::Response, du
     );
 }
 
-pub fn log_error(request: &rouille::Request, duration: Duration) {
-    let method = request.method();
-    let path = request.raw_url().split('?').next().unwrap();
+pub fn log_error(method: &str, path: &str, duration: Duration) {
     let time = duration.as_millis();
     warn!(
         "Handler panicked :{method} {path} - {time}ms",
@@ -33,142 +65,396 @@ pub fn log_error(request: &rouille::Request, duration: Duration) {
     );
 }
 
+/// Serve the request in its own task, so a panicking handler only fails its own request, and
+/// log how long it took.
+pub async fn handle(server: Arc<Server>, request: Request) -> Response {
+    let method = request.method().as_str().to_owned();
+    let path = request.uri().path().to_owned();
+    let start = Instant::now();
+    match tokio::spawn(server.serve_request(request)).await {
+        Ok(response) => {
+            log_success(&method, &path, start.elapsed());
+            response
+        }
+        Err(_) => {
+            log_error(&method, &path, start.elapsed());
+            response::empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 pub struct Server {
-    pub folder: PathBuf,
+    pub files: Box<dyn FileProvider>,
     pub http_client: isahc::HttpClient,
     pub proxies: Vec<ProxyConfig>,
+    pub mocks: Vec<MockConfig>,
+    pub throttle: Option<NetworkProfile>,
+    pub throttles: Vec<(glob::Pattern, NetworkProfile)>,
+    pub limits: Limits,
+}
+
+/// Limits on the requests handled by the server, checked before routing them.
+#[derive(Debug, Default)]
+pub struct Limits {
+    pub max_header_size: Option<usize>,
+    pub max_body_size: Option<u64>,
+}
+
+impl Limits {
+    /// The response to send instead of handling the request, if it is too large.
+    fn check(&self, request: &Request) -> Option<Response> {
+        if let Some(max) = self.max_header_size {
+            let size = request
+                .headers()
+                .iter()
+                .map(|(key, value)| key.as_str().len() + value.len() + 4)
+                .sum::<usize>()
+                + request.method().as_str().len()
+                + request.uri().to_string().len();
+            if size > max {
+                return Some(response::text(
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "Request Header Fields Too Large",
+                ));
+            }
+        }
+        if let Some(max) = self.max_body_size {
+            let content_length = request
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok())
+                .and_then(|length| length.parse::<u64>().ok());
+            if matches!(content_length, Some(length) if length > max) {
+                return Some(payload_too_large());
+            }
+        }
+        None
+    }
+
+    /// Read the body of a request answered by the server itself, which doesn't need it, to check
+    /// the size of a chunked body too. Proxies check it themselves while streaming it.
+    async fn check_body(&self, request: &mut Request) -> Option<Response> {
+        let max = self.max_body_size?;
+        let mut size = 0;
+        while let Some(chunk) = request.body_mut().data().await {
+            // the client is gone, there is no one to answer
+            size += chunk.ok()?.len() as u64;
+            if size > max {
+                return Some(payload_too_large());
+            }
+        }
+        None
+    }
 }
+
 impl Server {
-    pub fn new(folder: PathBuf, proxies: &HashMap<String, ProxyTarget>) -> Result<Arc<Self>> {
-        let metadata = fs::metadata(&folder)
-            .with_context(|| format!("folder `{}` not found", folder.display()))?;
-        anyhow::ensure!(metadata.is_dir(), "`{}` is not a folder", folder.display());
-        let http_client = isahc::HttpClient::new().expect("failed to build http client");
-        let proxies = proxies
+    pub fn new(files: Box<dyn FileProvider>, config: &Config, cache: &Cache) -> Result<Arc<Self>> {
+        let http_client = upstream::http_client()
+            .build()
+            .expect("failed to build http client");
+        let mut proxies = config
+            .proxies
+            .iter()
+            .map(|(key, val)| ProxyConfig::new(key, val, config.server.max_body_size, cache))
+            .collect::<Result<Vec<_>>>()?;
+        proxies.sort_by(ProxyConfig::precedence);
+        let mocks = config
+            .mocks
             .iter()
-            .map(|(key, val)| ProxyConfig::new(key, val))
+            .map(MockConfig::new)
+            .collect::<Result<_>>()?;
+        let throttles = config
+            .throttles
+            .iter()
+            .map(|rule| {
+                glob::Pattern::new(&rule.path)
+                    .with_context(|| format!("invalid glob: `{}`", rule.path))
+                    .map(|pattern| (pattern, rule.profile))
+            })
             .collect::<Result<_>>()?;
         Ok(Arc::new(Self {
-            folder,
+            files,
             http_client,
             proxies,
+            mocks,
+            throttle: config.server.throttle,
+            throttles,
+            limits: Limits {
+                max_header_size: config.server.max_header_size,
+                max_body_size: config.server.max_body_size,
+            },
         }))
     }
-    pub fn serve_request(self: &Arc<Self>, request: &rouille::Request) -> rouille::Response {
-        self.clone().inner_serve(request)
+    pub async fn serve_request(self: Arc<Self>, request: Request) -> Response {
+        if let Some(response) = self.limits.check(&request) {
+            return response;
+        }
+        self.inner_serve(request).await
     }
-    fn inner_serve(&self, request: &rouille::Request) -> rouille::Response {
+    async fn inner_serve(&self, mut request: Request) -> Response {
+        if admin::matches(&request) {
+            return admin::serve(self, &request);
+        }
+        for mock_config in self.mocks.iter() {
+            if let Some(params) = mock_config.matches(&request) {
+                if let Some(response) = self.limits.check_body(&mut request).await {
+                    return response;
+                }
+                return mock_config
+                    .serve(&request, &params)
+                    .await
+                    .unwrap_or_else(error_500);
+            }
+        }
         for proxy_config in self.proxies.iter() {
-            if proxy_config.matches(request) {
+            if proxy_config.matches(&request) {
+                if proxy_config.bypasses(&request) {
+                    debug!(
+                        "bypassing proxy {} for {}",
+                        proxy_config.path,
+                        request.uri()
+                    );
+                    break;
+                }
                 return proxy_config
                     .serve(request, &self.http_client)
+                    .await
                     .unwrap_or_else(error_500);
             }
         }
-        self.serve(request)
+        if let Some(response) = self.limits.check_body(&mut request).await {
+            return response;
+        }
+        self.serve(&request).await
     }
 
-    fn serve(&self, request: &rouille::Request) -> rouille::Response {
-        debug!("serving local file: {}", request.raw_url());
-        if wants_html(request) {
+    async fn serve(&self, request: &Request) -> Response {
+        debug!("serving local file: {}", request.uri());
+        let url = url_path(request);
+        let response = if wants_html(request) {
             // TODO: nice matching on url to find right html file
-            let path = self.folder.join("index.html");
-            serve_file(&path, mime::TEXT_HTML_UTF_8)
+            self.serve_file(Path::new("index.html"), mime::TEXT_HTML_UTF_8)
         } else {
-            let path = PathBuf::from(&request.url()[1..]);
+            let path = PathBuf::from(&url[1..]);
             let mime = mime_guess::from_path(&path)
                 .first()
                 .unwrap_or(mime::APPLICATION_OCTET_STREAM);
-            serve_file(&self.folder.join(&path), mime)
+            self.serve_file(&path, mime)
+        };
+        match self.network_profile(&url) {
+            Some(profile) => throttle::apply_profile(response, profile).await,
+            None => response,
         }
     }
+
+    fn serve_file(&self, file_path: &Path, mime: mime::Mime) -> Response {
+        self.files
+            .open(file_path)
+            .map(|content| {
+                let mut response = Response::new(content.body);
+                let headers = response.headers_mut();
+                headers.insert(
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_str(mime.as_ref()).expect("invalid mime"),
+                );
+                headers.insert(header::CONTENT_LENGTH, content.len.into());
+                response
+            })
+            .unwrap_or_else(response::not_found)
+    }
+
+    fn network_profile(&self, path: &str) -> Option<&NetworkProfile> {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        self.throttles
+            .iter()
+            .find(|(pattern, _)| pattern.matches_with(path, options))
+            .map(|(_, profile)| profile)
+            .or(self.throttle.as_ref())
+    }
+}
+
+/// The percent-decoded path of the request, without the query string.
+fn url_path(request: &Request) -> Cow<'_, str> {
+    percent_encoding::percent_decode_str(request.uri().path()).decode_utf8_lossy()
 }
 
-fn error_500(e: anyhow::Error) -> rouille::Response {
+fn error_500(e: anyhow::Error) -> Response {
     debug!(
         "raised an internal server error (code 500), caused by: {}",
         e
     );
-    rouille::Response::empty_400().with_status_code(500)
+    response::empty(StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-fn serve_file(file_path: &PathBuf, mime: mime::Mime) -> rouille::Response {
-    let mime = Cow::Owned(mime.as_ref().to_owned());
-    fs::File::open(file_path)
-        .map(|f| rouille::Response::from_file(mime, f))
-        .unwrap_or_else(|_| rouille::Response::empty_404())
+fn payload_too_large() -> Response {
+    response::text(StatusCode::PAYLOAD_TOO_LARGE, "Payload Too Large")
 }
 
-fn wants_html(request: &rouille::Request) -> bool {
-    if let Some(accept) = request.header("accept") {
-        rouille::input::parse_priority_header(accept)
-            .flat_map(|(mime, _)| mime.parse::<mime::Mime>().ok())
-            .any(|mime| mime.type_() == mime::TEXT && mime.subtype() == mime::HTML)
-    } else {
-        false
-    }
+fn wants_html(request: &Request) -> bool {
+    request
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .filter_map(|range| range.split(';').next())
+        .flat_map(|mime| mime.trim().parse::<mime::Mime>().ok())
+        .any(|mime| mime.type_() == mime::TEXT && mime.subtype() == mime::HTML)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
-    fn test_wants_html() {
-        use rouille::Request;
-        let r = Request::fake_http("GET", "/", vec![("accept".into(), "*/*".into())], vec![]);
-        assert!(!wants_html(&r));
-        let r = Request::fake_http(
-            "GET",
-            "/",
-            vec![("accept".into(), "text/html".into())],
-            vec![],
+
+    fn fake(headers: &[(&str, &str)]) -> Request {
+        let builder = headers.iter().fold(
+            hyper::Request::builder().method("GET").uri("/"),
+            |builder, (key, value)| builder.header(*key, *value),
         );
-        assert!(wants_html(&r));
-        let r = Request::fake_http(
-            "GET",
-            "/",
-            vec![("accept".into(), "text/html; charset=utf-8".into())],
-            vec![],
+        builder.body(hyper::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_network_profile() {
+        use crate::{
+            config::{self, NamedProfile, ThrottleRule},
+            provider::FolderProvider,
+        };
+        let folder = std::env::temp_dir();
+        let mut config = config::from_folder(folder.to_string_lossy().into_owned());
+        config.throttles.push(ThrottleRule {
+            path: "/assets/*.js".to_owned(),
+            profile: NetworkProfile::Custom {
+                kbps: 100,
+                latency: 0,
+            },
+        });
+        let cache = Cache::init_with_custom_path_for_test(folder.clone());
+        let files = || Box::new(FolderProvider::new(folder.clone()).unwrap());
+        let server = Server::new(files(), &config, &cache).unwrap();
+        assert_eq!(
+            server.network_profile("/assets/main.js"),
+            Some(&NetworkProfile::Custom {
+                kbps: 100,
+                latency: 0
+            })
         );
-        assert!(wants_html(&r));
-        let r = Request::fake_http(
-            "GET",
-            "/",
-            vec![(
-                "accept".into(),
-                "text/html; charset=utf-8, text/plain".into(),
-            )],
-            vec![],
+        assert_eq!(server.network_profile("/assets/vendor/main.js"), None);
+
+        config.server.throttle = Some(NetworkProfile::Named(NamedProfile::Slow3g));
+        let server = Server::new(files(), &config, &cache).unwrap();
+        assert_eq!(
+            server.network_profile("/assets/vendor/main.js"),
+            Some(&NetworkProfile::Named(NamedProfile::Slow3g))
         );
-        assert!(wants_html(&r));
-        let r = Request::fake_http(
-            "GET",
-            "/",
-            vec![(
-                "accept".into(),
-                " text/plain, text/html; charset=utf-8, */*".into(),
-            )],
-            vec![],
+    }
+    #[tokio::test]
+    async fn test_path_traversal() {
+        use crate::{config, provider::FolderProvider};
+        let root =
+            std::env::temp_dir().join(format!("spa-server-test-traversal-{}", std::process::id()));
+        let folder = root.join("dist");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("main.js"), "console.log()").unwrap();
+        std::fs::write(root.join("secret.txt"), "secret").unwrap();
+        let config = config::from_folder(folder.to_string_lossy().into_owned());
+        let cache = Cache::init_with_custom_path_for_test(root.clone());
+        let files = Box::new(FolderProvider::new(folder).unwrap());
+        let server = Server::new(files, &config, &cache).unwrap();
+        let get = |uri: &str| hyper::Request::get(uri).body(hyper::Body::empty()).unwrap();
+        assert_eq!(
+            server.serve(&get("/main.js")).await.status(),
+            StatusCode::OK
         );
-        assert!(wants_html(&r));
+        for uri in &["/../secret.txt", "/..%2fsecret.txt", "/%2e%2e/secret.txt"] {
+            let status = server.serve(&get(uri)).await.status();
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        }
+        std::fs::remove_dir_all(root).ok();
+    }
+
+    #[test]
+    fn test_wants_html() {
+        assert!(!wants_html(&fake(&[("accept", "*/*")])));
+        assert!(wants_html(&fake(&[("accept", "text/html")])));
+        assert!(wants_html(&fake(&[("accept", "text/html; charset=utf-8")])));
+        assert!(wants_html(&fake(&[(
+            "accept",
+            "text/html; charset=utf-8, text/plain"
+        )])));
+        assert!(wants_html(&fake(&[(
+            "accept",
+            " text/plain, text/html; charset=utf-8, */*"
+        )])));
         // taken from Firefox requesting an html file
-        let r = Request::fake_http(
-            "GET",
-            "/",
-            vec![(
-                "accept".into(),
-                "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8".into(),
-            )],
-            vec![],
-        );
-        assert!(wants_html(&r));
+        assert!(wants_html(&fake(&[(
+            "accept",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,image/webp,*/*;q=0.8"
+        )])));
         // taken from Firefox requesting a CSS file
-        let r = Request::fake_http(
-            "GET",
-            "/",
-            vec![("accept".into(), "text/css,*/*;q=0.1".into())],
-            vec![],
+        assert!(!wants_html(&fake(&[("accept", "text/css,*/*;q=0.1")])));
+        assert!(!wants_html(&fake(&[])));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_header_size: Some(64),
+            max_body_size: Some(4),
+        };
+        assert!(limits.check(&fake(&[])).is_none());
+        let big = "a".repeat(64);
+        let response = limits.check(&fake(&[("X-Big", &big)]));
+        assert_eq!(
+            response.unwrap().status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
         );
-        assert!(!wants_html(&r));
+        let response = limits.check(&fake(&[("Content-Length", "5")]));
+        assert_eq!(response.unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(limits.check(&fake(&[("Content-Length", "4")])).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body_limit() {
+        use crate::{
+            config::{self, MockRoute},
+            provider::FolderProvider,
+        };
+        let folder = std::env::temp_dir();
+        let mut config = config::from_folder(folder.to_string_lossy().into_owned());
+        config.server.max_body_size = Some(4);
+        config.mocks.push(MockRoute {
+            method: None,
+            path: "/api/mock".to_owned(),
+            status: 200,
+            headers: Default::default(),
+            body: Some("mocked".to_owned()),
+            json: None,
+            file: None,
+            template: false,
+        });
+        let cache = Cache::init_with_custom_path_for_test(folder.clone());
+        let files = Box::new(FolderProvider::new(folder).unwrap());
+        let server = Server::new(files, &config, &cache).unwrap();
+        let post = |uri: &str, chunks: Vec<&'static str>| {
+            let chunks = chunks.into_iter().map(Ok::<_, std::io::Error>);
+            hyper::Request::post(uri)
+                .body(hyper::Body::wrap_stream(futures_util::stream::iter(chunks)))
+                .unwrap()
+        };
+        for uri in &["/api/mock", "/missing.txt"] {
+            let response = server
+                .clone()
+                .serve_request(post(uri, vec!["ab", "cde"]))
+                .await;
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE, "{}", uri);
+        }
+        let response = server
+            .serve_request(post("/api/mock", vec!["ab", "cd"]))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
     }
 }
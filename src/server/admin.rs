@@ -0,0 +1,81 @@
+use super::{response, Request, Response, Server};
+use hyper::{Method, StatusCode};
+use serde::Serialize;
+
+/// All the admin endpoints are served under this prefix, before mocks and proxies.
+pub const PREFIX: &str = "/__spa-server/";
+
+#[derive(Debug, Serialize)]
+struct FaultStatus<'a> {
+    proxy: &'a str,
+    enabled: bool,
+}
+
+pub fn matches(request: &Request) -> bool {
+    request.uri().path().starts_with(PREFIX)
+}
+
+pub fn serve(server: &Server, request: &Request) -> Response {
+    let path = request.uri().path();
+    debug!("serving admin endpoint {} {}", request.method(), path);
+    match (request.method(), &path[PREFIX.len()..]) {
+        (&Method::GET, "faults") => fault_status(server),
+        (&Method::POST, "faults") => toggle_faults(server, request),
+        _ => response::not_found(),
+    }
+}
+
+fn fault_status(server: &Server) -> Response {
+    let status = server
+        .proxies
+        .iter()
+        .filter_map(|proxy| {
+            proxy.faults.as_ref().map(|faults| FaultStatus {
+                proxy: &proxy.path,
+                enabled: faults.is_enabled(),
+            })
+        })
+        .collect::<Vec<_>>();
+    response::json(&status)
+}
+
+fn toggle_faults(server: &Server, request: &Request) -> Response {
+    let enabled = match query_param(request, "enabled").as_deref() {
+        Some("true") | None => true,
+        Some("false") => false,
+        Some(other) => {
+            return response::text(
+                StatusCode::BAD_REQUEST,
+                format!("invalid value for `enabled`: {}", other),
+            )
+        }
+    };
+    let proxy_path = query_param(request, "proxy");
+    let mut found = false;
+    for proxy in server.proxies.iter() {
+        let targeted = proxy_path
+            .as_deref()
+            .map(|path| proxy.is_at(path))
+            .unwrap_or(true);
+        if let (true, Some(faults)) = (targeted, &proxy.faults) {
+            info!(
+                "{} faults for proxy {}",
+                if enabled { "enabling" } else { "disabling" },
+                proxy.path
+            );
+            faults.set_enabled(enabled);
+            found = true;
+        }
+    }
+    if found {
+        fault_status(server)
+    } else {
+        response::text(StatusCode::NOT_FOUND, "no proxy with faults found")
+    }
+}
+
+fn query_param(request: &Request, name: &str) -> Option<String> {
+    url::form_urlencoded::parse(request.uri().query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
@@ -0,0 +1,112 @@
+use crate::config::{AttributeRewrite, CookieConfig, SameSite};
+
+/// Rewrite the attributes of a `Set-Cookie` value according to the config. The name, the value
+/// and the other attributes are kept as they are.
+pub fn rewrite(config: &CookieConfig, cookie: &str) -> String {
+    let mut parts = cookie.split(';');
+    let mut rewritten = vec![parts.next().unwrap_or_default().trim().to_owned()];
+    for attribute in parts
+        .map(str::trim)
+        .filter(|attribute| !attribute.is_empty())
+    {
+        let mut split = attribute.splitn(2, '=');
+        let name = split.next().unwrap_or_default().trim();
+        let value = split.next().map(str::trim).unwrap_or_default();
+        let rewrite = if name.eq_ignore_ascii_case("domain") {
+            config.domain.as_ref()
+        } else if name.eq_ignore_ascii_case("path") {
+            config.path.as_ref()
+        } else if name.eq_ignore_ascii_case("secure") && config.secure.is_some()
+            || name.eq_ignore_ascii_case("samesite") && config.same_site.is_some()
+        {
+            // added back below if needed
+            continue;
+        } else {
+            None
+        };
+        match rewrite.and_then(|rewrite| replacement(rewrite, value)) {
+            Some("") => {}
+            Some(value) => rewritten.push(format!("{}={}", name, value)),
+            None => rewritten.push(attribute.to_owned()),
+        }
+    }
+    if config.secure == Some(true) {
+        rewritten.push("Secure".to_owned());
+    }
+    match config.same_site {
+        Some(SameSite::Strict) => rewritten.push("SameSite=Strict".to_owned()),
+        Some(SameSite::Lax) => rewritten.push("SameSite=Lax".to_owned()),
+        Some(SameSite::None) => rewritten.push("SameSite=None".to_owned()),
+        Some(SameSite::Remove) | None => {}
+    }
+    rewritten.join("; ")
+}
+
+/// The new value of an attribute, `None` when it is kept.
+fn replacement<'a>(rewrite: &'a AttributeRewrite, value: &str) -> Option<&'a str> {
+    match rewrite {
+        AttributeRewrite::All(new) => Some(new),
+        AttributeRewrite::Map(map) => map
+            .iter()
+            .find(|(old, _)| old.eq_ignore_ascii_case(value))
+            .or_else(|| map.iter().find(|(old, _)| old.as_str() == "*"))
+            .map(|(_, new)| new.as_str()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn map(pairs: &[(&str, &str)]) -> AttributeRewrite {
+        AttributeRewrite::Map(
+            pairs
+                .iter()
+                .map(|(old, new)| (old.to_string(), new.to_string()))
+                .collect::<HashMap<_, _>>(),
+        )
+    }
+
+    #[test]
+    fn cookie_rewrite() {
+        let cookie = "session=abc=; Domain=api.internal; Path=/v1; Secure; HttpOnly; SameSite=None";
+        assert_eq!(rewrite(&CookieConfig::default(), cookie), cookie);
+
+        let config = CookieConfig {
+            domain: Some(AttributeRewrite::All(String::new())),
+            path: Some(map(&[("/v1", "/api")])),
+            secure: Some(false),
+            same_site: Some(SameSite::Lax),
+        };
+        assert_eq!(
+            rewrite(&config, cookie),
+            "session=abc=; Path=/api; HttpOnly; SameSite=Lax"
+        );
+
+        let config = CookieConfig {
+            domain: Some(map(&[("api.internal", "localhost")])),
+            path: Some(map(&[("/v2", "/api")])),
+            secure: Some(true),
+            same_site: Some(SameSite::Remove),
+        };
+        assert_eq!(
+            rewrite(&config, "id=1;domain=API.internal;path=/v1"),
+            "id=1; domain=localhost; path=/v1; Secure"
+        );
+        assert_eq!(
+            rewrite(&config, "id=1; Domain=other.internal; Secure"),
+            "id=1; Domain=other.internal; Secure"
+        );
+
+        let config = CookieConfig {
+            domain: Some(map(&[("api.internal", "localhost"), ("*", "")])),
+            ..Default::default()
+        };
+        assert_eq!(rewrite(&config, "id=1; Domain=other.internal"), "id=1");
+        assert_eq!(
+            rewrite(&config, "id=1; Domain=api.internal"),
+            "id=1; Domain=localhost"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use super::{response, throttle, Response};
+use crate::config::FaultConfig;
+use anyhow::Result;
+use futures_util::stream;
+use hyper::{Body, StatusCode};
+use rand::Rng;
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+#[derive(Debug)]
+pub struct Faults {
+    pub config: FaultConfig,
+    enabled: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Error(u16),
+    Reset,
+}
+
+impl Faults {
+    pub fn new(config: &FaultConfig) -> Result<Self> {
+        anyhow::ensure!(
+            (0.0..=100.0).contains(&config.error_rate),
+            "`error_rate` must be a percentage, got {}",
+            config.error_rate
+        );
+        anyhow::ensure!(
+            (0.0..=100.0).contains(&config.reset_rate),
+            "`reset_rate` must be a percentage, got {}",
+            config.reset_rate
+        );
+        anyhow::ensure!(
+            config.error_rate + config.reset_rate <= 100.0,
+            "`error_rate` and `reset_rate` must not add up to more than 100"
+        );
+        anyhow::ensure!(
+            (100..600).contains(&config.error_status),
+            "invalid status code: {}",
+            config.error_status
+        );
+        if let Some(delay_max) = config.delay_max {
+            anyhow::ensure!(
+                delay_max >= config.delay,
+                "`delay_max` must be greater than `delay`"
+            );
+        }
+        Ok(Self {
+            config: config.clone(),
+            enabled: AtomicBool::new(config.enabled),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed)
+    }
+
+    pub fn delay(&self) -> Duration {
+        let delay = match self.config.delay_max {
+            Some(delay_max) => rand::thread_rng().gen_range(self.config.delay..=delay_max),
+            None => self.config.delay,
+        };
+        Duration::from_millis(delay)
+    }
+
+    /// Randomly pick a fault to inject, following the configured rates.
+    pub fn pick(&self) -> Option<Fault> {
+        let roll = rand::thread_rng().gen_range(0.0..100.0);
+        if roll < self.config.error_rate {
+            Some(Fault::Error(self.config.error_status))
+        } else if roll < self.config.error_rate + self.config.reset_rate {
+            Some(Fault::Reset)
+        } else {
+            None
+        }
+    }
+
+    pub fn apply(&self, response: Response) -> Response {
+        match self.config.bandwidth {
+            Some(bandwidth) => throttle::throttle(response, bandwidth * 1024),
+            None => response,
+        }
+    }
+}
+
+impl Fault {
+    pub fn into_response(self) -> Response {
+        match self {
+            Fault::Error(status) => response::text(
+                StatusCode::from_u16(status).expect("status is validated"),
+                format!("fault injected by spa-server ({})", status),
+            ),
+            Fault::Reset => Response::new(reset_body()),
+        }
+    }
+}
+
+/// A body failing on the first read, so that the connection is dropped by the server.
+fn reset_body() -> Body {
+    Body::wrap_stream(stream::once(async {
+        Err::<Vec<u8>, _>(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "connection reset injected by spa-server",
+        ))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> FaultConfig {
+        FaultConfig {
+            enabled: true,
+            delay: 0,
+            delay_max: None,
+            error_rate: 0.0,
+            error_status: 502,
+            reset_rate: 0.0,
+            bandwidth: None,
+        }
+    }
+
+    #[test]
+    fn faults_new_validates() {
+        assert!(Faults::new(&config()).is_ok());
+        let mut c = config();
+        c.error_rate = 101.0;
+        assert!(Faults::new(&c).is_err());
+        let mut c = config();
+        c.error_rate = 60.0;
+        c.reset_rate = 60.0;
+        assert!(Faults::new(&c).is_err());
+        let mut c = config();
+        c.delay = 100;
+        c.delay_max = Some(50);
+        assert!(Faults::new(&c).is_err());
+    }
+
+    #[test]
+    fn faults_pick() {
+        let faults = Faults::new(&config()).unwrap();
+        assert_eq!(faults.pick(), None);
+        let mut c = config();
+        c.error_rate = 100.0;
+        c.error_status = 503;
+        let faults = Faults::new(&c).unwrap();
+        assert_eq!(faults.pick(), Some(Fault::Error(503)));
+        let mut c = config();
+        c.reset_rate = 100.0;
+        let faults = Faults::new(&c).unwrap();
+        assert_eq!(faults.pick(), Some(Fault::Reset));
+    }
+
+    #[test]
+    fn faults_delay() {
+        let mut c = config();
+        c.delay = 10;
+        assert_eq!(Faults::new(&c).unwrap().delay(), Duration::from_millis(10));
+        c.delay_max = Some(20);
+        let delay = Faults::new(&c).unwrap().delay();
+        assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,346 @@
+use super::{Request, Response};
+use crate::config::MockRoute;
+use anyhow::{Context, Result};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use mime_guess::mime;
+use std::{collections::HashMap, path::PathBuf};
+
+#[derive(Debug)]
+pub struct MockConfig {
+    pub method: Option<String>,
+    pub path: String,
+    segments: Vec<Segment>,
+    pub status: u16,
+    pub headers: HeaderMap,
+    body: MockBody,
+    pub template: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest,
+}
+
+#[derive(Debug)]
+enum MockBody {
+    Empty,
+    Inline { content: String, mime: mime::Mime },
+    File { path: PathBuf, mime: mime::Mime },
+}
+
+pub type Params = HashMap<String, String>;
+
+impl MockConfig {
+    pub fn new(mock: &MockRoute) -> Result<Self> {
+        let path = &mock.path;
+        anyhow::ensure!(path.starts_with('/'), "path `{}` is not a valid path", path);
+        let segments = parse_segments(path)?;
+        let method = mock.method.as_ref().map(|m| m.to_ascii_uppercase());
+        let body_count = [
+            mock.body.is_some(),
+            mock.json.is_some(),
+            mock.file.is_some(),
+        ]
+        .iter()
+        .filter(|b| **b)
+        .count();
+        anyhow::ensure!(
+            body_count <= 1,
+            "mock `{}` must have at most one of `body`, `json` or `file`",
+            path
+        );
+        let body = if let Some(body) = &mock.body {
+            MockBody::Inline {
+                content: body.clone(),
+                mime: mime::TEXT_PLAIN_UTF_8,
+            }
+        } else if let Some(json) = &mock.json {
+            let content = serde_json::to_string_pretty(json)
+                .with_context(|| format!("mock `{}` has an invalid json body", path))?;
+            MockBody::Inline {
+                content,
+                mime: mime::APPLICATION_JSON,
+            }
+        } else if let Some(file) = &mock.file {
+            let expanded = shellexpand::full(file)
+                .with_context(|| format!("failed to expand path: {}", file))?;
+            let path = PathBuf::from(expanded.as_ref());
+            let mime = mime_guess::from_path(&path)
+                .first()
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+            MockBody::File { path, mime }
+        } else {
+            MockBody::Empty
+        };
+        anyhow::ensure!(
+            (100..600).contains(&mock.status),
+            "invalid status code: {}",
+            mock.status
+        );
+        let mut headers = HeaderMap::new();
+        for (key, value) in &mock.headers {
+            headers.insert(
+                HeaderName::from_bytes(key.as_bytes())
+                    .with_context(|| format!("invalid header name in mock: {}", key))?,
+                HeaderValue::from_str(value)
+                    .with_context(|| format!("invalid header value in mock: {}", value))?,
+            );
+        }
+        Ok(Self {
+            method,
+            path: path.clone(),
+            segments,
+            status: mock.status,
+            headers,
+            body,
+            template: mock.template,
+        })
+    }
+
+    /// Returns the captured path parameters if the request matches this mock.
+    pub fn matches(&self, request: &Request) -> Option<Params> {
+        if let Some(method) = &self.method {
+            if method != request.method().as_str() {
+                return None;
+            }
+        }
+        match_segments(&self.segments, &super::url_path(request))
+    }
+
+    pub async fn serve(&self, request: &Request, params: &Params) -> Result<Response> {
+        debug!("serving mock {} for {}", self.path, request.uri());
+        let (content, mime) = match &self.body {
+            MockBody::Empty => (Vec::new(), None),
+            MockBody::Inline { content, mime } => (content.clone().into_bytes(), Some(mime)),
+            MockBody::File { path, mime } => {
+                let content = tokio::fs::read(path)
+                    .await
+                    .with_context(|| format!("failed to read mock file `{}`", path.display()))?;
+                (content, Some(mime))
+            }
+        };
+        // only a template must be text, files like images are sent as they are
+        let content = if self.template {
+            let content = String::from_utf8(content)
+                .with_context(|| format!("the template of mock `{}` is not UTF-8", self.path))?;
+            let json = mime.is_some_and(|mime| {
+                mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON)
+            });
+            render_template(&content, params, json).into_bytes()
+        } else {
+            content
+        };
+        let mut response = Response::new(content.into());
+        *response.status_mut() = hyper::StatusCode::from_u16(self.status)?;
+        let headers = response.headers_mut();
+        if let Some(mime) = mime {
+            headers.insert(CONTENT_TYPE, HeaderValue::from_str(mime.as_ref())?);
+        }
+        for (key, value) in &self.headers {
+            headers.insert(key, value.clone());
+        }
+        Ok(response)
+    }
+}
+
+fn parse_segments(path: &str) -> Result<Vec<Segment>> {
+    let parts = path[1..].split('/').collect::<Vec<_>>();
+    let last = parts.len() - 1;
+    parts
+        .iter()
+        .enumerate()
+        .map(|(idx, part)| {
+            if *part == "*" {
+                anyhow::ensure!(idx == last, "`*` must be at the end of path `{}`", path);
+                Ok(Segment::Rest)
+            } else if let Some(name) = part.strip_prefix(':') {
+                anyhow::ensure!(!name.is_empty(), "unnamed parameter in path `{}`", path);
+                Ok(Segment::Param(name.to_owned()))
+            } else {
+                Ok(Segment::Literal((*part).to_owned()))
+            }
+        })
+        .collect()
+}
+
+fn match_segments(segments: &[Segment], url: &str) -> Option<Params> {
+    let mut params = Params::new();
+    let mut parts = url.strip_prefix('/').unwrap_or(url).split('/');
+    for segment in segments {
+        match segment {
+            Segment::Rest => return Some(params),
+            Segment::Literal(literal) => {
+                if parts.next()? != literal {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                let part = parts.next().filter(|p| !p.is_empty())?;
+                params.insert(name.clone(), part.to_owned());
+            }
+        }
+    }
+    if parts.next().is_none() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+/// Replace the `{{name}}` tokens by the captured parameters, in a single pass so the values are
+/// never expanded themselves. The values are escaped for json bodies, as they are put inside
+/// strings. Unknown tokens are left as they are.
+fn render_template(content: &str, params: &Params, json: bool) -> String {
+    let mut rendered = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let token = &rest[start..];
+        let end = match token.find("}}") {
+            Some(end) => end + 2,
+            None => {
+                rest = token;
+                break;
+            }
+        };
+        match params.get(&token[2..end - 2]) {
+            Some(value) if json => {
+                let quoted = serde_json::Value::from(value.as_str()).to_string();
+                rendered.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(value) => rendered.push_str(value),
+            None => rendered.push_str(&token[..end]),
+        }
+        rest = &token[end..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock(method: Option<&str>, path: &str) -> MockConfig {
+        MockConfig::new(&MockRoute {
+            method: method.map(str::to_owned),
+            path: path.to_owned(),
+            status: 200,
+            headers: Default::default(),
+            body: None,
+            json: None,
+            file: None,
+            template: false,
+        })
+        .unwrap()
+    }
+
+    fn fake(method: &str, url: &str) -> Request {
+        hyper::Request::builder()
+            .method(method)
+            .uri(url)
+            .body(hyper::Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn mock_matches() {
+        let m = mock(Some("get"), "/api/users/:id");
+        let params = m.matches(&fake("GET", "/api/users/42")).unwrap();
+        assert_eq!(params.get("id").map(String::as_str), Some("42"));
+        assert!(m.matches(&fake("GET", "/api/users/42?full=true")).is_some());
+        assert!(m.matches(&fake("POST", "/api/users/42")).is_none());
+        assert!(m.matches(&fake("GET", "/api/users/")).is_none());
+        assert!(m.matches(&fake("GET", "/api/users/42/posts")).is_none());
+
+        let m = mock(None, "/api/*");
+        assert!(m.matches(&fake("DELETE", "/api/users/42")).is_some());
+        assert!(m.matches(&fake("GET", "/api/")).is_some());
+        assert!(m.matches(&fake("GET", "/other")).is_none());
+    }
+
+    #[test]
+    fn mock_new_errors() {
+        let route = |path: &str| MockRoute {
+            method: None,
+            path: path.to_owned(),
+            status: 200,
+            headers: Default::default(),
+            body: Some("hello".to_owned()),
+            json: None,
+            file: Some("hello.txt".to_owned()),
+            template: false,
+        };
+        assert!(MockConfig::new(&route("/api")).is_err());
+        let mut r = route("api");
+        r.file = None;
+        assert!(MockConfig::new(&r).is_err());
+        let mut r = route("/api/*/users");
+        r.file = None;
+        assert!(MockConfig::new(&r).is_err());
+        let mut r = route("/api");
+        r.file = None;
+        r.headers.insert("x bad".to_owned(), "value".to_owned());
+        assert!(MockConfig::new(&r).is_err());
+        let mut r = route("/api");
+        r.file = None;
+        r.headers
+            .insert("x-bad".to_owned(), "line\nbreak".to_owned());
+        assert!(MockConfig::new(&r).is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_binary_file() {
+        let file =
+            std::env::temp_dir().join(format!("spa-server-test-mock-{}.png", std::process::id()));
+        let content = b"\x89PNG\r\n\x1a\n\xff\x00";
+        std::fs::write(&file, content).unwrap();
+        let route = |template| MockRoute {
+            method: None,
+            path: "/avatar".to_owned(),
+            status: 200,
+            headers: Default::default(),
+            body: None,
+            json: None,
+            file: Some(file.display().to_string()),
+            template,
+        };
+        let request = fake("GET", "/avatar");
+        let m = MockConfig::new(&route(false)).unwrap();
+        let response = m.serve(&request, &Params::new()).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_TYPE], "image/png");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], &content[..]);
+
+        let m = MockConfig::new(&route(true)).unwrap();
+        assert!(m.serve(&request, &Params::new()).await.is_err());
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn mock_template() {
+        let mut params = Params::new();
+        params.insert("id".to_owned(), "42".to_owned());
+        assert_eq!(
+            render_template(r#"{"id": "{{id}}", "other": "{{other}}"}"#, &params, true),
+            r#"{"id": "42", "other": "{{other}}"}"#
+        );
+        // a captured value is not expanded again
+        params.insert("name".to_owned(), "{{id}}".to_owned());
+        assert_eq!(
+            render_template("{{name}} {{id}} {{", &params, false),
+            "{{id}} 42 {{"
+        );
+        params.insert("name".to_owned(), "say \"hi\"\\".to_owned());
+        assert_eq!(
+            render_template(r#"{"name": "{{name}}"}"#, &params, true),
+            r#"{"name": "say \"hi\"\\"}"#
+        );
+        assert_eq!(
+            render_template("<p>{{name}}</p>", &params, false),
+            "<p>say \"hi\"\\</p>"
+        );
+    }
+}
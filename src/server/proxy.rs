@@ -1,151 +1,897 @@
-use crate::config::ProxyTarget;
+use super::{
+    cookie,
+    fault::Faults,
+    record::{self, Recorder, Recording},
+    response,
+    upload::{self, UploadError},
+    upstream::{Upstream, UpstreamTls, Upstreams},
+    websocket, Peer, Request, Response,
+};
+use crate::{
+    cache::Cache,
+    config::{BypassConfig, CookieConfig, ProxyTarget, RecordMode},
+};
 use anyhow::{Context, Result};
-use isahc::{http, HttpClient};
-use std::{borrow::Cow, collections::HashMap, io::Read as _};
+use futures_util::{AsyncReadExt as _, StreamExt as _};
+use hyper::{
+    body::HttpBody as _,
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    http::request::Parts,
+    Body, StatusCode, Uri,
+};
+use isahc::{config::Configurable as _, http, HttpClient};
+use regex::Regex;
+use std::{borrow::Cow, cmp::Ordering, fmt, net::IpAddr, time::Duration};
+use tokio_util::{compat::FuturesAsyncReadCompatExt as _, io::ReaderStream};
 
 #[derive(Debug)]
 pub struct ProxyConfig {
     pub path: String,
-    pub target: String,
-    pub path_rewrite: Option<(String, String)>,
-    pub headers: HashMap<String, String>,
+    matcher: PathMatcher,
+    priority: i32,
+    pub upstreams: Upstreams,
+    tls: Option<UpstreamTls>,
+    pub path_rewrite: Option<PathRewrite>,
+    pub headers: HeaderMap,
+    pub recorder: Option<Recorder>,
+    pub faults: Option<Faults>,
+    cookies: Option<CookieConfig>,
+    rewrite_location: bool,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    retries: u32,
+    pub ws: bool,
+    bypass: Option<Bypass>,
+    pub max_body_size: Option<u64>,
+}
+
+/// The requests served by the application instead of the target.
+#[derive(Debug)]
+struct Bypass {
+    html: bool,
+    paths: Vec<glob::Pattern>,
+    methods: Vec<String>,
+}
+
+impl Bypass {
+    fn new(config: &BypassConfig) -> Result<Self> {
+        let paths = config
+            .paths
+            .iter()
+            .map(|path| {
+                glob::Pattern::new(path).with_context(|| format!("invalid glob: `{}`", path))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            html: config.html,
+            paths,
+            methods: config
+                .methods
+                .iter()
+                .map(|method| method.to_ascii_uppercase())
+                .collect(),
+        })
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let path = request.uri().path();
+        self.html && super::wants_html(request)
+            || self
+                .paths
+                .iter()
+                .any(|pattern| pattern.matches_with(path, options))
+            || self
+                .methods
+                .iter()
+                .any(|method| method == request.method().as_str())
+    }
+}
+
+/// Why the target did not answer.
+#[derive(Debug)]
+enum UpstreamError {
+    /// No response within the `timeout` of the proxy.
+    Timeout(Duration),
+    Failed(isahc::Error),
+    /// A target on a unix socket failed, they are not reached with the http client.
+    Unix(std::io::Error),
+}
+
+impl UpstreamError {
+    fn status(&self) -> StatusCode {
+        match self {
+            UpstreamError::Timeout(_) | UpstreamError::Failed(isahc::Error::Timeout) => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            UpstreamError::Failed(_) | UpstreamError::Unix(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Whether the target was not reached at all, so the request can safely be sent again.
+    fn is_retryable(&self) -> bool {
+        match self {
+            UpstreamError::Failed(e) => matches!(
+                e,
+                isahc::Error::ConnectFailed | isahc::Error::NoResponse | isahc::Error::Timeout
+            ),
+            UpstreamError::Unix(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ),
+            UpstreamError::Timeout(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Timeout(timeout) => write!(f, "no response after {:?}", timeout),
+            UpstreamError::Failed(e) => e.fmt(f),
+            UpstreamError::Unix(e) => e.fmt(f),
+        }
+    }
+}
+
+/// The delay before the first retry, doubled for each next one, up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// How the path of a request is compared to the path of the proxy.
+#[derive(Debug)]
+enum PathMatcher {
+    /// The request path starts with the proxy path, on a segment boundary.
+    Prefix,
+    /// The proxy path is a glob, where `*` doesn't match `/` and `**` matches any segments.
+    Glob(glob::Pattern),
+    /// The proxy path starts with `^` and is a regex.
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+pub enum PathRewrite {
+    Regex(Regex, String),
+    StripPrefix,
 }
 
 impl ProxyConfig {
-    pub fn new(path: &str, proxy: &ProxyTarget) -> Result<Self> {
-        anyhow::ensure!(path.starts_with('/'), "path `{}` is not a valid path", path);
+    pub fn new(
+        path: &str,
+        proxy: &ProxyTarget,
+        max_body_size: Option<u64>,
+        cache: &Cache,
+    ) -> Result<Self> {
+        anyhow::ensure!(
+            path.starts_with('/') || path.starts_with('^'),
+            "path `{}` is not a valid path",
+            path
+        );
         let mut path = path.to_owned();
-        if !path.ends_with('/') {
-            path += "/";
-        }
-        rouille::url::Url::parse(&proxy.target)
-            .with_context(|| format!("invalid target: `{}`", &proxy.target))?;
-        let target = proxy.target.clone();
-        let path_rewrite = proxy.path_rewrite.clone();
-        let headers = proxy.headers.clone();
+        let matcher = if path.starts_with('^') {
+            PathMatcher::Regex(
+                Regex::new(&path).with_context(|| format!("invalid regex: `{}`", path))?,
+            )
+        } else if path.contains(&['*', '?', '['][..]) {
+            PathMatcher::Glob(
+                glob::Pattern::new(&path).with_context(|| format!("invalid glob: `{}`", path))?,
+            )
+        } else {
+            if !path.ends_with('/') {
+                path += "/";
+            }
+            PathMatcher::Prefix
+        };
+        anyhow::ensure!(
+            !proxy.strip_prefix || matches!(matcher, PathMatcher::Prefix),
+            "proxy `{}` can only use `strip_prefix` with a plain path",
+            path
+        );
+        let upstreams = Upstreams::new(proxy)
+            .with_context(|| format!("invalid targets for proxy `{}`", path))?;
+        let tls = UpstreamTls::new(proxy)
+            .with_context(|| format!("invalid TLS settings for proxy `{}`", path))?;
+        let path_rewrite = match (&proxy.path_rewrite, proxy.strip_prefix) {
+            (Some(_), true) => anyhow::bail!(
+                "proxy `{}` can't have both `path_rewrite` and `strip_prefix`",
+                path
+            ),
+            (Some((pattern, replacement)), false) => Some(PathRewrite::Regex(
+                Regex::new(pattern)
+                    .with_context(|| format!("invalid regex in `path_rewrite`: `{}`", pattern))?,
+                replacement.clone(),
+            )),
+            (None, true) => Some(PathRewrite::StripPrefix),
+            (None, false) => None,
+        };
+        let mut headers = HeaderMap::new();
+        for (key, value) in &proxy.headers {
+            headers.insert(
+                HeaderName::from_bytes(key.as_bytes())
+                    .with_context(|| format!("invalid header name in proxy: {}", key))?,
+                HeaderValue::from_str(value)
+                    .with_context(|| format!("invalid header value in proxy: {}", value))?,
+            );
+        }
+        let recorder = proxy
+            .record
+            .as_ref()
+            .map(|record| Recorder::new(record, &path, cache))
+            .transpose()?;
+        let faults = proxy.faults.as_ref().map(Faults::new).transpose()?;
+        let bypass = proxy
+            .bypass
+            .as_ref()
+            .map(Bypass::new)
+            .transpose()
+            .with_context(|| format!("invalid bypass for proxy `{}`", path))?;
         Ok(Self {
             path,
-            target,
+            matcher,
+            priority: proxy.priority,
+            upstreams,
+            tls,
             path_rewrite,
             headers,
+            recorder,
+            faults,
+            cookies: proxy.cookies.clone(),
+            rewrite_location: proxy.rewrite_location,
+            connect_timeout: proxy.connect_timeout.map(Duration::from_millis),
+            timeout: proxy.timeout.map(Duration::from_millis),
+            retries: proxy.retries,
+            ws: proxy.ws,
+            bypass,
+            max_body_size,
+        })
+    }
+
+    pub fn matches(&self, request: &Request) -> bool {
+        let path = request.uri().path();
+        match &self.matcher {
+            PathMatcher::Prefix => {
+                path.starts_with(&self.path) || path == &self.path[..self.path.len() - 1]
+            }
+            PathMatcher::Glob(pattern) => {
+                let options = glob::MatchOptions {
+                    require_literal_separator: true,
+                    ..Default::default()
+                };
+                pattern.matches_with(path, options)
+            }
+            PathMatcher::Regex(regex) => regex.is_match(path),
+        }
+    }
+
+    /// Whether the proxy is the one configured at `path`. Plain paths can be given with or
+    /// without their trailing `/`, globs and regexes must be given as they are in the config.
+    pub fn is_at(&self, path: &str) -> bool {
+        match self.matcher {
+            PathMatcher::Prefix => self.path == path || self.path.strip_suffix('/') == Some(path),
+            PathMatcher::Glob(_) | PathMatcher::Regex(_) => self.path == path,
+        }
+    }
+
+    /// Whether the request matches the proxy, but must be served by the application.
+    pub fn bypasses(&self, request: &Request) -> bool {
+        self.bypass
+            .as_ref()
+            .is_some_and(|bypass| bypass.matches(request))
+    }
+
+    /// The order in which the proxies are tried: highest `priority` first, then the most specific
+    /// path, i.e. the one with the longest literal prefix, then the longest path. Remaining ties
+    /// are broken by the path itself, so the order never depends on the order of the config.
+    pub fn precedence(&self, other: &Self) -> Ordering {
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| other.specificity().cmp(&self.specificity()))
+            .then_with(|| self.path.cmp(&other.path))
+    }
+
+    fn specificity(&self) -> (usize, usize) {
+        let special: &[char] = match self.matcher {
+            PathMatcher::Prefix => return (self.path.len(), self.path.len()),
+            PathMatcher::Glob(_) => &['*', '?', '['],
+            PathMatcher::Regex(_) => &[
+                '.', '*', '+', '?', '(', ')', '[', ']', '{', '}', '|', '\\', '$',
+            ],
+        };
+        let literal = self.path.trim_start_matches('^');
+        let literal = literal.find(special).unwrap_or(literal.len());
+        (literal, self.path.len())
+    }
+
+    pub async fn serve(&self, request: Request, http_client: &HttpClient) -> Result<Response> {
+        let faults = self.faults.as_ref().filter(|faults| faults.is_enabled());
+        if let Some(faults) = faults {
+            let delay = faults.delay();
+            if delay.as_millis() > 0 {
+                trace!("delaying request at {} by {:?}", request.uri(), delay);
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(fault) = faults.pick() {
+                debug!("injecting {:?} for request at {}", fault, request.uri());
+                return Ok(fault.into_response());
+            }
+        }
+        let response = self.forward(request, http_client).await?;
+        Ok(match faults {
+            Some(faults) => faults.apply(response),
+            None => response,
         })
     }
 
-    pub fn matches(&self, request: &rouille::Request) -> bool {
-        request.raw_url().starts_with(&self.path)
-            || request.raw_url() == &self.path[..self.path.len() - 1]
+    async fn forward(&self, request: Request, http_client: &HttpClient) -> Result<Response> {
+        let http_client = match &self.tls {
+            Some(tls) => &tls.http_client,
+            None => http_client,
+        };
+        if self.ws && websocket::is_upgrade(&request) {
+            let upstream = self.upstreams.pick(None);
+            let path = self.upstream_path(request.uri());
+            let peer = request.extensions().get();
+            let mut headers = self.upstream_headers(upstream, request.headers(), peer);
+            // the only hop-by-hop headers that must go through, to ask the target for the upgrade
+            headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            if let Some(upgrade) = request.headers().get(header::UPGRADE) {
+                headers.insert(header::UPGRADE, upgrade.clone());
+            }
+            let uri = request.uri().clone();
+            let connector = self.tls.as_ref().map(|tls| &tls.connector);
+            let forward = websocket::forward(
+                request,
+                &upstream.target,
+                &path,
+                headers,
+                connector,
+                self.connect_timeout,
+                self.timeout,
+            );
+            return Ok(match forward.await {
+                Ok(response) => {
+                    self.upstreams.succeeded(upstream);
+                    response
+                }
+                Err(e) => {
+                    self.upstreams.failed(upstream);
+                    let status = if e.is::<websocket::Timeout>() {
+                        StatusCode::GATEWAY_TIMEOUT
+                    } else {
+                        StatusCode::BAD_GATEWAY
+                    };
+                    let reason = format!("{:#}", e);
+                    self.error_response(upstream, &uri, status, &reason)
+                }
+            });
+        }
+        if let Some(recorder) = &self.recorder {
+            return self.serve_recorded(request, http_client, recorder).await;
+        }
+        let (parts, body) = request.into_parts();
+        // the body is streamed, so it can't be sent again
+        let retries = if body.is_end_stream() && is_idempotent(&parts.method) {
+            self.retries
+        } else {
+            0
+        };
+        let (body, upload) = upload::stream(body, content_length(&parts), self.max_body_size);
+        let mut upstream = self.upstreams.pick(None);
+        debug!("proxying request at {} to {}", parts.uri, upstream.target);
+        let mut result = self
+            .send(
+                upstream,
+                self.upstream_request(upstream, &parts, body),
+                http_client,
+            )
+            .await;
+        for attempt in 0..retries {
+            match &result {
+                Err(e) if e.is_retryable() => {
+                    debug!("retrying request at {} after: {}", parts.uri, e);
+                    tokio::time::sleep(retry_delay(attempt)).await;
+                }
+                _ => break,
+            }
+            upstream = self.upstreams.pick(Some(upstream));
+            let req = self.upstream_request(upstream, &parts, isahc::Body::empty());
+            result = self.send(upstream, req, http_client).await;
+        }
+        let res = match result {
+            Ok(res) => res,
+            Err(e) => {
+                return Ok(match upload.error() {
+                    Some(UploadError::TooLarge) => super::payload_too_large(),
+                    Some(UploadError::Client(reason)) => {
+                        debug!("failed to read the body at {}: {}", parts.uri, reason);
+                        response::text(StatusCode::BAD_REQUEST, "failed to read the request body")
+                    }
+                    None => self.error_response(upstream, &parts.uri, e.status(), &e),
+                });
+            }
+        };
+        Ok(self.downstream_response(upstream, res))
     }
 
-    pub fn serve(
+    async fn serve_recorded(
         &self,
-        request: &rouille::Request,
+        request: Request,
         http_client: &HttpClient,
-    ) -> Result<rouille::Response> {
-        debug!("proxying request at {} to {}", request.url(), self.target);
-        let req = self.rouille_to_http(request);
-        let res = http_client.send(req);
-        if let Err(e) = &res {
-            warn!("failed to proxy request to {}: {}", self.target, e);
-        }
-        let res = res?;
-        Ok(self.http_to_rouille(res))
-    }
-
-    fn rouille_to_http(&self, req: &rouille::Request) -> http::Request<isahc::Body> {
-        let builder = http::Request::builder()
-            .method(req.method())
-            .uri(self.target.clone() + req.raw_url());
-        let builder = req
-            .headers()
-            .fold(builder, |builder, (key, value)| builder.header(key, value));
-        let builder = self
-            .headers
-            .iter()
-            .fold(builder, |builder, (key, value)| builder.header(key, value));
-        let mut data = req.data().expect("no data found");
-        let mut buffer = Vec::new();
-        let size = data
-            .read_to_end(&mut buffer)
-            .expect("failed to read from incoming request");
-        if size == 0 {
-            builder
-                .body(isahc::Body::empty())
-                .expect("failed to build request")
+        recorder: &Recorder,
+    ) -> Result<Response> {
+        let (parts, body) = request.into_parts();
+        let body = match read_body(body, self.max_body_size).await? {
+            Some(body) => body,
+            None => return Ok(super::payload_too_large()),
+        };
+        let raw_url = parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/");
+        let key = recorder.key(parts.method.as_str(), raw_url, &body);
+        match recorder.mode {
+            RecordMode::Replay => {
+                debug!("replaying request at {} ({})", parts.uri, key);
+                if let Some(recording) = recorder.load(&key)? {
+                    recording.into_response()
+                } else {
+                    warn!("no recording found for {} {}", parts.method, raw_url);
+                    Ok(response::text(
+                        StatusCode::NOT_FOUND,
+                        format!("no recording found for {} {}", parts.method, raw_url),
+                    ))
+                }
+            }
+            RecordMode::Record => {
+                debug!("recording request at {} ({})", parts.uri, key);
+                let upstream = self.upstreams.pick(None);
+                let req = self.upstream_request(upstream, &parts, isahc::Body::from(body));
+                let mut res = match self.send(upstream, req, http_client).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        return Ok(self.error_response(upstream, &parts.uri, e.status(), &e));
+                    }
+                };
+                if is_event_stream(res.headers(), header::CONTENT_TYPE) {
+                    // it only ends when the client leaves, so it can't be stored first
+                    debug!("not recording the event stream at {}", parts.uri);
+                    return Ok(self.downstream_response(upstream, res));
+                }
+                remove_hop_by_hop(res.headers_mut());
+                let status = res.status().as_u16();
+                let headers = record::header_pairs(res.headers());
+                let mut data = Vec::new();
+                res.into_body()
+                    .read_to_end(&mut data)
+                    .await
+                    .context("failed to read response to record")?;
+                let recording = Recording {
+                    method: parts.method.as_str().to_owned(),
+                    url: raw_url.to_owned(),
+                    status,
+                    headers,
+                    body: base64::encode(&data),
+                };
+                recorder.save(&key, &recording)?;
+                recording.into_response()
+            }
+        }
+    }
+
+    /// Send the request, and wait for the response within the `timeout` of the proxy. Only the
+    /// status and headers must be received in time, the body can then be streamed for as long as
+    /// needed. The outcome is counted in the health of the target.
+    async fn send(
+        &self,
+        upstream: &Upstream,
+        req: http::Request<isahc::Body>,
+        http_client: &HttpClient,
+    ) -> std::result::Result<http::Response<isahc::Body>, UpstreamError> {
+        let res = async {
+            match &upstream.unix {
+                #[cfg(unix)]
+                Some(socket) => super::unix::send(socket, req)
+                    .await
+                    .map_err(UpstreamError::Unix),
+                #[cfg(not(unix))]
+                Some(_) => unreachable!("unix targets are rejected by the config"),
+                None => http_client
+                    .send_async(req)
+                    .await
+                    .map_err(UpstreamError::Failed),
+            }
+        };
+        let res = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, res).await {
+                Ok(res) => res,
+                Err(_) => Err(UpstreamError::Timeout(timeout)),
+            },
+            None => res.await,
+        };
+        match &res {
+            Ok(_) => self.upstreams.succeeded(upstream),
+            Err(_) => self.upstreams.failed(upstream),
+        }
+        res
+    }
+
+    /// The response when the target can't be reached, telling which target failed and why so it
+    /// doesn't look like a bug of the frontend.
+    fn error_response(
+        &self,
+        upstream: &Upstream,
+        uri: &Uri,
+        status: StatusCode,
+        reason: &dyn fmt::Display,
+    ) -> Response {
+        let target = upstream.target.trim_end_matches('/').to_owned() + &self.upstream_path(uri);
+        warn!(
+            "failed to proxy request at {} to {}: {}",
+            uri, target, reason
+        );
+        response::text(
+            status,
+            format!(
+                "{} {}\n\nspa-server failed to proxy {} to {}\n{}\n",
+                status.as_u16(),
+                status.canonical_reason().unwrap_or_default(),
+                uri,
+                target,
+                reason
+            ),
+        )
+    }
+
+    /// The path and query sent to the target, after applying the rewrite rules.
+    fn upstream_path(&self, uri: &Uri) -> String {
+        let path = uri.path();
+        let path = match &self.path_rewrite {
+            Some(PathRewrite::Regex(regex, replacement)) => {
+                regex.replace(path, replacement.as_str())
+            }
+            Some(PathRewrite::StripPrefix) => Cow::Borrowed(
+                path.strip_prefix(&self.path[..self.path.len() - 1])
+                    .unwrap_or(path),
+            ),
+            None => Cow::Borrowed(path),
+        };
+        let slash = if path.starts_with('/') { "" } else { "/" };
+        match uri.query() {
+            Some(query) => format!("{}{}?{}", slash, path, query),
+            None => format!("{}{}", slash, path),
+        }
+    }
+
+    /// A location pointing to the target, changed to point to the same resource through the
+    /// proxy. It is made relative, so it stays on the origin the client requested, whatever it
+    /// is. `None` if the location points elsewhere.
+    fn downstream_location(&self, upstream: &Upstream, location: &str) -> Option<String> {
+        let url;
+        let path = if location.starts_with('/') && !location.starts_with("//") {
+            location
         } else {
-            builder
-                .body(isahc::Body::from_bytes(&buffer))
-                .expect("failed to build request")
+            url = url::Url::parse(location).ok()?;
+            let same_target = url.host_str() == upstream.url.host_str()
+                && url.port_or_known_default() == upstream.url.port_or_known_default();
+            if !same_target {
+                return None;
+            }
+            &url[url::Position::BeforePath..]
+        };
+        let base = upstream.url.path().trim_end_matches('/');
+        let path = match path.strip_prefix(base) {
+            Some("") => "/",
+            Some(rest) if rest.starts_with(&['/', '?', '#'][..]) => rest,
+            _ => path,
+        };
+        Some(match self.path_rewrite {
+            Some(PathRewrite::StripPrefix) => {
+                let prefix = &self.path[..self.path.len() - 1];
+                match path {
+                    "/" => format!("{}/", prefix),
+                    path => format!("{}{}", prefix, path),
+                }
+            }
+            _ => path.to_owned(),
+        })
+    }
+
+    /// The headers sent to the target: the end-to-end ones of the request, with the forwarding
+    /// headers describing the client, then the configured ones. The framing headers are left to
+    /// the client, which computes them from the body.
+    fn upstream_headers(
+        &self,
+        upstream: &Upstream,
+        headers: &HeaderMap,
+        peer: Option<&Peer>,
+    ) -> HeaderMap {
+        let peer = peer.copied().unwrap_or_default();
+        let proto = if peer.tls { "https" } else { "http" };
+        let host = headers.get(header::HOST).cloned();
+        let mut headers = headers.clone();
+        remove_hop_by_hop(&mut headers);
+        headers.remove(header::CONTENT_LENGTH);
+
+        let client = peer.addr.map(|addr| addr.ip());
+        if let Some(ip) = client {
+            append_to_list(&mut headers, "x-forwarded-for", &ip.to_string());
+        }
+        headers
+            .entry("x-forwarded-proto")
+            .or_insert_with(|| HeaderValue::from_static(proto));
+        if let Some(host) = &host {
+            headers
+                .entry("x-forwarded-host")
+                .or_insert_with(|| host.clone());
+        }
+        let mut forwarded = match client {
+            Some(IpAddr::V4(ip)) => format!("for={}", ip),
+            Some(IpAddr::V6(ip)) => format!("for=\"[{}]\"", ip),
+            None => "for=unknown".to_owned(),
+        };
+        if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+            forwarded += &format!(";host=\"{}\"", host);
+        }
+        forwarded += ";proto=";
+        forwarded += proto;
+        append_to_list(&mut headers, header::FORWARDED.as_str(), &forwarded);
+
+        if let Some(host) = &upstream.host {
+            headers.insert(header::HOST, host.clone());
+        }
+        // compressing an event stream makes most backends hold the events until a block is full
+        if is_event_stream(&headers, header::ACCEPT) {
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static("identity"),
+            );
+        }
+        for (key, value) in &self.headers {
+            headers.insert(key, value.clone());
         }
+        headers
     }
 
-    fn http_to_rouille(&self, res: http::Response<isahc::Body>) -> rouille::Response {
-        let status_code = res.status().as_u16();
-        let headers = res
-            .headers()
-            .iter()
-            .map(|(key, value)| {
-                let key = Cow::Owned(key.as_str().to_owned());
-                let value = Cow::Owned(
-                    value
+    fn upstream_request(
+        &self,
+        upstream: &Upstream,
+        parts: &Parts,
+        body: isahc::Body,
+    ) -> http::Request<isahc::Body> {
+        let mut builder = http::Request::builder()
+            .method(parts.method.clone())
+            .uri(upstream.url(&self.upstream_path(&parts.uri)));
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        let mut request = builder.body(body).expect("failed to build request");
+        *request.headers_mut() =
+            self.upstream_headers(upstream, &parts.headers, parts.extensions.get());
+        request
+    }
+
+    /// The response sent back to the client, without the hop-by-hop headers of the target, and
+    /// with its cookies and locations rewritten if configured. The body is streamed with its
+    /// original `Content-Length` when there is one, otherwise hyper picks the framing for the
+    /// client's connection. Either way, each chunk is sent as soon as the target sends it.
+    fn downstream_response(
+        &self,
+        upstream: &Upstream,
+        res: http::Response<isahc::Body>,
+    ) -> Response {
+        let (mut parts, body) = res.into_parts();
+        remove_hop_by_hop(&mut parts.headers);
+        parts.version = http::Version::HTTP_11;
+        if let Some(config) = &self.cookies {
+            let cookies = parts
+                .headers
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .map(|cookie| {
+                    cookie
                         .to_str()
-                        .expect("header value is not valid utf-8")
-                        .to_owned(),
-                );
-                (key, value)
-            })
-            .collect::<Vec<_>>();
-        let body = res.into_body();
-        let data = if body.is_empty() {
-            rouille::ResponseBody::empty()
+                        .ok()
+                        .and_then(|value| {
+                            HeaderValue::from_str(&cookie::rewrite(config, value)).ok()
+                        })
+                        .unwrap_or_else(|| cookie.clone())
+                })
+                .collect::<Vec<_>>();
+            parts.headers.remove(header::SET_COOKIE);
+            for cookie in cookies {
+                parts.headers.append(header::SET_COOKIE, cookie);
+            }
+        }
+        if self.rewrite_location {
+            for name in &[header::LOCATION, header::CONTENT_LOCATION] {
+                let location = parts
+                    .headers
+                    .get(name)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| self.downstream_location(upstream, location))
+                    .and_then(|location| HeaderValue::from_str(&location).ok());
+                if let Some(location) = location {
+                    parts.headers.insert(name, location);
+                }
+            }
+        }
+        if is_event_stream(&parts.headers, header::CONTENT_TYPE) {
+            // or a proxy in front, like nginx, would buffer the events
+            parts
+                .headers
+                .entry("x-accel-buffering")
+                .or_insert_with(|| HeaderValue::from_static("no"));
+        }
+        let body = if body.is_empty() {
+            Body::empty()
         } else {
-            rouille::ResponseBody::from_reader(body)
+            Body::wrap_stream(ReaderStream::new(body.compat()))
         };
-        rouille::Response {
-            status_code,
-            headers,
-            data,
-            upgrade: None,
+        Response::from_parts(parts, body)
+    }
+}
+
+/// Read the whole body of the request, or `None` if it is bigger than `limit`. Only used for
+/// recordings, the other requests are streamed.
+async fn read_body(mut body: Body, limit: Option<u64>) -> Result<Option<Vec<u8>>> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("failed to read from incoming request")?;
+        buffer.extend_from_slice(&chunk);
+        if matches!(limit, Some(limit) if buffer.len() as u64 > limit) {
+            return Ok(None);
         }
     }
+    Ok(Some(buffer))
+}
+
+/// Whether sending the request several times has the same effect as sending it once.
+fn is_idempotent(method: &http::Method) -> bool {
+    use http::Method;
+    [
+        Method::GET,
+        Method::HEAD,
+        Method::OPTIONS,
+        Method::TRACE,
+        Method::PUT,
+        Method::DELETE,
+    ]
+    .contains(method)
+}
+
+fn content_length(parts: &Parts) -> Option<u64> {
+    parts
+        .headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse().ok())
+}
+
+/// Whether the header lists the media type of server-sent events.
+fn is_event_stream(headers: &HeaderMap, name: header::HeaderName) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media| media.split(';').next())
+        .any(|media| media.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// Headers only meaningful for a single connection, which a proxy must not forward (RFC 7230,
+/// section 6.1). `Proxy-Connection` is not standard, but still sent by some clients.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Remove the hop-by-hop headers, including the ones listed in `Connection`.
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+/// Add a value to a comma-separated header, merging the existing lines into one.
+fn append_to_list(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let list = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .chain(std::iter::once(value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(list) = HeaderValue::from_str(&list) {
+        headers.insert(name, list);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn cache() -> Cache {
+        Cache::init_with_custom_path_for_test(std::env::temp_dir())
+    }
+
+    fn fake(method: &str, url: &str) -> Request {
+        hyper::Request::builder()
+            .method(method)
+            .uri(url)
+            .body(Body::empty())
+            .unwrap()
+    }
+
     #[test]
     fn proxy_config_new() {
         let valid_proxy = ProxyConfig::new(
             "/api/",
             &ProxyTarget {
                 target: "http://localhost:8080".to_owned(),
-                path_rewrite: None,
-                headers: Default::default(),
+                ..Default::default()
             },
+            None,
+            &cache(),
         )
         .unwrap();
         assert_eq!(&valid_proxy.path, "/api/");
-        assert_eq!(&valid_proxy.target, "http://localhost:8080");
+        assert_eq!(
+            &valid_proxy.upstreams.first().target,
+            "http://localhost:8080"
+        );
 
         let valid_proxy = ProxyConfig::new(
             "/api",
             &ProxyTarget {
                 target: "http://localhost:8080".to_owned(),
-                path_rewrite: None,
-                headers: Default::default(),
+                ..Default::default()
             },
+            None,
+            &cache(),
         )
         .unwrap();
         assert_eq!(&valid_proxy.path, "/api/");
-        assert_eq!(&valid_proxy.target, "http://localhost:8080");
+        assert_eq!(
+            &valid_proxy.upstreams.first().target,
+            "http://localhost:8080"
+        );
 
         let error = ProxyConfig::new(
             "api",
             &ProxyTarget {
                 target: "http://localhost:8080".to_owned(),
-                path_rewrite: None,
-                headers: Default::default(),
+                ..Default::default()
             },
+            None,
+            &cache(),
         )
         .unwrap_err();
         assert_eq!(error.to_string(), "path `api` is not a valid path");
@@ -154,14 +900,15 @@ mod tests {
             "/api",
             &ProxyTarget {
                 target: "/localhost".to_owned(),
-                path_rewrite: None,
-                headers: Default::default(),
+                ..Default::default()
             },
+            None,
+            &cache(),
         )
         .unwrap_err();
         assert!(matches!(
-            error.downcast::<rouille::url::ParseError>(),
-            Ok(rouille::url::ParseError::RelativeUrlWithoutBase)
+            error.downcast::<url::ParseError>(),
+            Ok(url::ParseError::RelativeUrlWithoutBase)
         ));
     }
 
@@ -171,26 +918,1077 @@ mod tests {
             "/api",
             &ProxyTarget {
                 target: "http://localhost:8080".to_owned(),
-                path_rewrite: None,
-                headers: Default::default(),
+                ..Default::default()
             },
+            None,
+            &cache(),
         )
         .unwrap();
 
-        assert!(proxy.matches(&rouille::Request::fake_http("GET", "/api", vec![], vec![])));
-        assert!(proxy.matches(&rouille::Request::fake_http("GET", "/api/", vec![], vec![])));
-        assert!(proxy.matches(&rouille::Request::fake_http(
-            "GET",
-            "/api/endpoint",
-            vec![],
-            vec![]
-        )));
-        assert!(!proxy.matches(&rouille::Request::fake_http("GET", "/", vec![], vec![])));
-        assert!(!proxy.matches(&rouille::Request::fake_http(
-            "GET",
-            "/script.js",
-            vec![],
-            vec![]
-        )));
+        assert!(proxy.matches(&fake("GET", "/api")));
+        assert!(proxy.matches(&fake("GET", "/api/")));
+        assert!(proxy.matches(&fake("GET", "/api/endpoint")));
+        assert!(proxy.matches(&fake("GET", "/api?query")));
+        assert!(!proxy.matches(&fake("GET", "/")));
+        assert!(!proxy.matches(&fake("GET", "/script.js")));
+    }
+
+    #[test]
+    fn proxy_pattern_matches() {
+        let proxy = |path: &str| {
+            ProxyConfig::new(
+                path,
+                &ProxyTarget {
+                    target: "http://localhost:8080".to_owned(),
+                    ..Default::default()
+                },
+                None,
+                &cache(),
+            )
+            .unwrap()
+        };
+        let glob = proxy("/api/*/ws");
+        assert!(glob.matches(&fake("GET", "/api/chat/ws")));
+        assert!(glob.matches(&fake("GET", "/api/chat/ws?token=1")));
+        assert!(!glob.matches(&fake("GET", "/api/chat/room/ws")));
+        assert!(!glob.matches(&fake("GET", "/api/ws")));
+        let glob = proxy("/api/**/ws");
+        assert!(glob.matches(&fake("GET", "/api/chat/room/ws")));
+
+        let regex = proxy("^/v[0-9]+/");
+        assert!(regex.matches(&fake("GET", "/v2/users")));
+        assert!(!regex.matches(&fake("GET", "/vendor/users")));
+        assert!(!regex.matches(&fake("GET", "/api/v2/users")));
+
+        let invalid = ProxyTarget {
+            target: "http://localhost:8080".to_owned(),
+            ..Default::default()
+        };
+        assert!(ProxyConfig::new("^/v(", &invalid, None, &cache()).is_err());
+        assert!(ProxyConfig::new("/api/[", &invalid, None, &cache()).is_err());
+    }
+
+    #[test]
+    fn proxy_is_at() {
+        let proxy = |path: &str| {
+            let target = ProxyTarget {
+                target: "http://localhost:8080".to_owned(),
+                ..Default::default()
+            };
+            ProxyConfig::new(path, &target, None, &cache()).unwrap()
+        };
+        assert!(proxy("/api").is_at("/api"));
+        assert!(proxy("/api").is_at("/api/"));
+        assert!(proxy("/api/").is_at("/api"));
+        assert!(!proxy("/api").is_at("/ap"));
+        assert!(proxy("/api/*/ws").is_at("/api/*/ws"));
+        assert!(!proxy("/api/*/ws").is_at("/api/*/w"));
+        assert!(proxy("^/café").is_at("^/café"));
+        assert!(!proxy("^/café").is_at("^/caf"));
+    }
+
+    #[test]
+    fn proxy_bypass() {
+        let target = |bypass| ProxyTarget {
+            target: "http://localhost:8080".to_owned(),
+            bypass,
+            ..Default::default()
+        };
+        let navigation = |url| {
+            let mut request = fake("GET", url);
+            request.headers_mut().insert(
+                header::ACCEPT,
+                HeaderValue::from_static("text/html,application/xhtml+xml,*/*;q=0.8"),
+            );
+            request
+        };
+        let proxy = ProxyConfig::new("/api", &target(None), None, &cache()).unwrap();
+        assert!(!proxy.bypasses(&navigation("/api/ui")));
+
+        let bypass = BypassConfig {
+            html: true,
+            paths: vec!["/api/docs/**".to_owned()],
+            methods: vec!["options".to_owned()],
+        };
+        let proxy = ProxyConfig::new("/api", &target(Some(bypass)), None, &cache()).unwrap();
+        assert!(proxy.bypasses(&navigation("/api/ui")));
+        assert!(!proxy.bypasses(&fake("GET", "/api/ui")));
+        assert!(proxy.bypasses(&fake("GET", "/api/docs/v1/index.js")));
+        assert!(!proxy.bypasses(&fake("GET", "/api/users")));
+        assert!(proxy.bypasses(&fake("OPTIONS", "/api/users")));
+        assert!(!proxy.bypasses(&fake("POST", "/api/users")));
+
+        let bypass = BypassConfig {
+            paths: vec!["/api/[".to_owned()],
+            ..Default::default()
+        };
+        assert!(ProxyConfig::new("/api", &target(Some(bypass)), None, &cache()).is_err());
+    }
+
+    #[test]
+    fn proxy_precedence() {
+        let proxy = |path: &str, priority| {
+            ProxyConfig::new(
+                path,
+                &ProxyTarget {
+                    target: "http://localhost:8080".to_owned(),
+                    priority,
+                    ..Default::default()
+                },
+                None,
+                &cache(),
+            )
+            .unwrap()
+        };
+        let mut proxies = [
+            proxy("/api", 0),
+            proxy("/api/*/ws", 0),
+            proxy("/", 0),
+            proxy("/api/auth", 0),
+            proxy("^/api/auth/v[0-9]", 0),
+            proxy("/static", 10),
+        ];
+        proxies.sort_by(ProxyConfig::precedence);
+        let paths = proxies.iter().map(|p| p.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "/static/",
+                "^/api/auth/v[0-9]",
+                "/api/auth/",
+                "/api/*/ws",
+                "/api/",
+                "/"
+            ]
+        );
+        let first = |url| {
+            proxies
+                .iter()
+                .find(|p| p.matches(&fake("GET", url)))
+                .unwrap()
+        };
+        assert_eq!(first("/api/auth/login").path, "/api/auth/");
+        assert_eq!(first("/api/users").path, "/api/");
+        assert_eq!(first("/static/app.js").path, "/static/");
+        assert_eq!(first("/index.html").path, "/");
+    }
+
+    fn upstream_uri(proxy: &ProxyTarget, url: &str) -> String {
+        let proxy = ProxyConfig::new("/api", proxy, None, &cache()).unwrap();
+        let (parts, _) = fake("GET", url).into_parts();
+        proxy
+            .upstream_request(proxy.upstreams.first(), &parts, isahc::Body::empty())
+            .uri()
+            .to_string()
+    }
+
+    #[test]
+    fn proxy_path_rewrite() {
+        let target = |path_rewrite: Option<(&str, &str)>, strip_prefix| ProxyTarget {
+            target: "http://localhost:8080".to_owned(),
+            path_rewrite: path_rewrite.map(|(from, to)| (from.to_owned(), to.to_owned())),
+            strip_prefix,
+            ..Default::default()
+        };
+        assert_eq!(
+            upstream_uri(&target(None, false), "/api/users?page=2"),
+            "http://localhost:8080/api/users?page=2"
+        );
+
+        let rewrite = target(Some(("^/api/(.*)$", "/v2/$1")), false);
+        assert_eq!(
+            upstream_uri(&rewrite, "/api/users/42?page=2"),
+            "http://localhost:8080/v2/users/42?page=2"
+        );
+        let rewrite = target(Some(("^/api/(?P<rest>.*)$", "/${rest}.json")), false);
+        assert_eq!(
+            upstream_uri(&rewrite, "/api/users"),
+            "http://localhost:8080/users.json"
+        );
+        let rewrite = target(Some(("^/api", "")), false);
+        assert_eq!(upstream_uri(&rewrite, "/api"), "http://localhost:8080/");
+
+        let strip = target(None, true);
+        assert_eq!(
+            upstream_uri(&strip, "/api/users?page=2"),
+            "http://localhost:8080/users?page=2"
+        );
+        assert_eq!(upstream_uri(&strip, "/api"), "http://localhost:8080/");
+        assert_eq!(
+            upstream_uri(&strip, "/api?page=2"),
+            "http://localhost:8080/?page=2"
+        );
+
+        let both = target(Some(("^/api", "")), true);
+        assert!(ProxyConfig::new("/api", &both, None, &cache()).is_err());
+        let invalid = target(Some(("^/api/(", "")), false);
+        assert!(ProxyConfig::new("/api", &invalid, None, &cache()).is_err());
+    }
+
+    #[test]
+    fn proxy_forwarding_headers() {
+        let proxy = |change_origin| {
+            let mut headers = std::collections::HashMap::new();
+            headers.insert("X-Custom".to_owned(), "custom".to_owned());
+            ProxyConfig::new(
+                "/api",
+                &ProxyTarget {
+                    target: "https://api.example.com:8443/base".to_owned(),
+                    change_origin,
+                    headers,
+                    ..Default::default()
+                },
+                None,
+                &cache(),
+            )
+            .unwrap()
+        };
+        let request = |peer: Option<Peer>, headers: &[(&str, &str)]| {
+            let mut request = headers
+                .iter()
+                .fold(hyper::Request::builder().uri("/api"), |builder, (k, v)| {
+                    builder.header(*k, *v)
+                })
+                .body(Body::empty())
+                .unwrap();
+            if let Some(peer) = peer {
+                request.extensions_mut().insert(peer);
+            }
+            let (parts, _) = request.into_parts();
+            parts
+        };
+        let send = |proxy: &ProxyConfig, parts: &Parts| {
+            proxy
+                .upstream_request(proxy.upstreams.first(), parts, isahc::Body::empty())
+                .headers()
+                .clone()
+        };
+
+        let parts = request(
+            Some(Peer {
+                addr: Some("192.168.1.2:54321".parse().unwrap()),
+                tls: false,
+            }),
+            &[("Host", "localhost:4242")],
+        );
+        let headers = send(&proxy(false), &parts);
+        assert_eq!(headers["host"], "localhost:4242");
+        assert_eq!(headers["x-forwarded-for"], "192.168.1.2");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "localhost:4242");
+        assert_eq!(
+            headers["forwarded"],
+            "for=192.168.1.2;host=\"localhost:4242\";proto=http"
+        );
+        assert_eq!(headers["x-custom"], "custom");
+        let headers = send(&proxy(true), &parts);
+        assert_eq!(headers["host"], "api.example.com:8443");
+        assert_eq!(headers["x-forwarded-host"], "localhost:4242");
+
+        // behind another proxy, the client is added to the existing lists
+        let parts = request(
+            Some(Peer {
+                addr: Some("[::1]:54321".parse().unwrap()),
+                tls: true,
+            }),
+            &[
+                ("Host", "localhost:4242"),
+                ("X-Forwarded-For", "10.0.0.1"),
+                ("X-Forwarded-For", "10.0.0.2"),
+                ("X-Forwarded-Proto", "https"),
+                ("Forwarded", "for=10.0.0.1"),
+            ],
+        );
+        let headers = send(&proxy(false), &parts);
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1, 10.0.0.2, ::1");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(
+            headers["forwarded"],
+            "for=10.0.0.1, for=\"[::1]\";host=\"localhost:4242\";proto=https"
+        );
+
+        // on a unix socket
+        let headers = send(&proxy(false), &request(None, &[]));
+        assert!(headers.get("x-forwarded-for").is_none());
+        assert_eq!(headers["forwarded"], "for=unknown;proto=http");
+    }
+
+    #[test]
+    fn proxy_hop_by_hop_request() {
+        let proxy = ProxyConfig::new(
+            "/api",
+            &ProxyTarget {
+                target: "http://localhost:8080".to_owned(),
+                ..Default::default()
+            },
+            None,
+            &cache(),
+        )
+        .unwrap();
+        let (parts, _) = hyper::Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .header("Host", "localhost:4242")
+            .header("Connection", "keep-alive, X-Secret")
+            .header("Keep-Alive", "timeout=5")
+            .header("X-Secret", "hop")
+            .header("Transfer-Encoding", "chunked")
+            .header("TE", "trailers")
+            .header("Proxy-Authorization", "Basic Zm9vOmJhcg==")
+            .header("Proxy-Connection", "keep-alive")
+            .header("Content-Type", "text/plain")
+            .header("Authorization", "Bearer token")
+            .body(Body::empty())
+            .unwrap()
+            .into_parts();
+        let request =
+            proxy.upstream_request(proxy.upstreams.first(), &parts, isahc::Body::from("hello"));
+        let headers = request.headers();
+        for name in &[
+            "connection",
+            "keep-alive",
+            "x-secret",
+            "transfer-encoding",
+            "te",
+            "proxy-authorization",
+            "proxy-connection",
+            "content-length",
+        ] {
+            assert!(headers.get(*name).is_none(), "{} was forwarded", name);
+        }
+        assert_eq!(headers["host"], "localhost:4242");
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(headers["authorization"], "Bearer token");
+        assert_eq!(request.body().len(), Some(5));
+    }
+
+    #[tokio::test]
+    async fn proxy_hop_by_hop_response() {
+        let proxy = ProxyConfig::new(
+            "/api",
+            &ProxyTarget {
+                target: "http://localhost:8080".to_owned(),
+                ..Default::default()
+            },
+            None,
+            &cache(),
+        )
+        .unwrap();
+        let upstream = http::Response::builder()
+            .version(http::Version::HTTP_10)
+            .header("Connection", "keep-alive, X-Secret")
+            .header("Keep-Alive", "timeout=5")
+            .header("X-Secret", "hop")
+            .header("Transfer-Encoding", "chunked")
+            .header("Upgrade", "h2c")
+            .header("Trailer", "X-Checksum")
+            .header("Proxy-Authenticate", "Basic")
+            .header("Content-Type", "text/plain")
+            .header("Set-Cookie", "a=1")
+            .header("Set-Cookie", "b=2")
+            .body(isahc::Body::from("hello"))
+            .unwrap();
+        let response = proxy.downstream_response(proxy.upstreams.first(), upstream);
+        assert_eq!(response.version(), http::Version::HTTP_11);
+        let headers = response.headers();
+        for name in HOP_BY_HOP.iter().chain(&["x-secret"]) {
+            assert!(headers.get(*name).is_none(), "{} was forwarded", name);
+        }
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(headers.get_all("set-cookie").iter().count(), 2);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[test]
+    fn proxy_rewrite_location() {
+        let proxy = |target: &str, strip_prefix| {
+            ProxyConfig::new(
+                "/api",
+                &ProxyTarget {
+                    target: target.to_owned(),
+                    strip_prefix,
+                    rewrite_location: true,
+                    ..Default::default()
+                },
+                None,
+                &cache(),
+            )
+            .unwrap()
+        };
+        let proxy_base = proxy("http://api.internal/v1", true);
+        let location = |proxy: &ProxyConfig, location| {
+            proxy.downstream_location(proxy.upstreams.first(), location)
+        };
+        assert_eq!(
+            location(&proxy_base, "http://api.internal/v1/login?next=/home"),
+            Some("/api/login?next=/home".to_owned())
+        );
+        assert_eq!(
+            location(&proxy_base, "http://api.internal:80/v1"),
+            Some("/api/".to_owned())
+        );
+        assert_eq!(
+            location(&proxy_base, "/v1/login"),
+            Some("/api/login".to_owned())
+        );
+        assert_eq!(location(&proxy_base, "https://api.internal/v1/login"), None);
+        assert_eq!(
+            location(&proxy_base, "https://auth.example.com/login"),
+            None
+        );
+
+        let proxy_root = proxy("http://localhost:8080", false);
+        assert_eq!(
+            location(&proxy_root, "http://localhost:8080/api/login"),
+            Some("/api/login".to_owned())
+        );
+        assert_eq!(location(&proxy_root, "http://localhost:9090/login"), None);
+
+        let upstream = http::Response::builder()
+            .status(302)
+            .header("Location", "http://api.internal/v1/login")
+            .header("Content-Location", "http://api.internal/v1/users/1")
+            .body(isahc::Body::empty())
+            .unwrap();
+        let response = proxy_base.downstream_response(proxy_base.upstreams.first(), upstream);
+        assert_eq!(response.headers()["location"], "/api/login");
+        assert_eq!(response.headers()["content-location"], "/api/users/1");
+    }
+
+    #[test]
+    fn proxy_rewrite_cookies() {
+        use crate::config::{AttributeRewrite, SameSite};
+        let proxy = ProxyConfig::new(
+            "/api",
+            &ProxyTarget {
+                target: "http://api.internal/v1".to_owned(),
+                cookies: Some(CookieConfig {
+                    domain: Some(AttributeRewrite::All(String::new())),
+                    secure: Some(false),
+                    same_site: Some(SameSite::Lax),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            None,
+            &cache(),
+        )
+        .unwrap();
+        let upstream = http::Response::builder()
+            .header("Set-Cookie", "a=1; Domain=api.internal; Secure")
+            .header("Set-Cookie", "b=2; Path=/v1; SameSite=None")
+            .header("Set-Cookie", &b"c=\xe9"[..])
+            .body(isahc::Body::empty())
+            .unwrap();
+        let response = proxy.downstream_response(proxy.upstreams.first(), upstream);
+        let cookies = response
+            .headers()
+            .get_all("set-cookie")
+            .iter()
+            .map(|cookie| cookie.as_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            cookies,
+            [
+                &b"a=1; SameSite=Lax"[..],
+                b"b=2; Path=/v1; SameSite=Lax",
+                b"c=\xe9"
+            ]
+        );
+    }
+
+    fn proxy_to(addr: std::net::SocketAddr, timeout: Option<u64>, retries: u32) -> ProxyConfig {
+        ProxyConfig::new(
+            "/api",
+            &ProxyTarget {
+                target: format!("http://{}", addr),
+                timeout,
+                retries,
+                ..Default::default()
+            },
+            None,
+            &cache(),
+        )
+        .unwrap()
+    }
+
+    fn client() -> HttpClient {
+        crate::server::upstream::http_client().build().unwrap()
+    }
+
+    async fn body_text(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn proxy_bad_gateway() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = proxy_to(addr, None, 2);
+        let client = client();
+        let start = std::time::Instant::now();
+        let response = proxy
+            .serve(fake("GET", "/api/users?page=2"), &client)
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= RETRY_DELAY * 3);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let body = body_text(response).await;
+        assert!(body.starts_with("502 Bad Gateway"), "{}", body);
+        assert!(
+            body.contains(&format!("http://{}/api/users?page=2", addr)),
+            "{}",
+            body
+        );
+        assert!(body.contains("ConnectFailed"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn proxy_compressed_body() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write as _;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"hello from the backend\n").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let length = gzipped.len();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let gzipped = gzipped.clone();
+                let service = hyper::service::service_fn(move |request: Request| {
+                    let gzipped = gzipped.clone();
+                    async move {
+                        let response = match request.headers().get(header::ACCEPT_ENCODING) {
+                            Some(encoding) if encoding == "gzip" => hyper::Response::builder()
+                                .header(header::CONTENT_ENCODING, "gzip")
+                                .header(header::CONTENT_LENGTH, gzipped.len())
+                                .body(Body::from(gzipped)),
+                            _ => hyper::Response::builder().body(Body::from("identity")),
+                        };
+                        Ok::<_, std::convert::Infallible>(response.unwrap())
+                    }
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+        let proxy = proxy_to(addr, None, 0);
+        let client = client();
+        let mut request = fake("GET", "/api");
+        request
+            .headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = proxy.serve(request, &client).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers()[header::CONTENT_LENGTH],
+            length.to_string().as_str()
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body.len(), length);
+        let mut decoded = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&body[..]), &mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello from the backend\n");
+
+        // no encoding asked by the client
+        let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+        assert_eq!(body_text(response).await, "identity");
+    }
+
+    #[tokio::test]
+    async fn proxy_gateway_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let proxy = proxy_to(addr, Some(200), 0);
+        let client = client();
+        let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(body_text(response)
+            .await
+            .contains("no response after 200ms"));
+
+        let target = ProxyTarget {
+            target: format!("http://{}", addr),
+            timeout: Some(200),
+            ws: true,
+            ..Default::default()
+        };
+        let proxy = ProxyConfig::new("/api", &target, None, &cache()).unwrap();
+        let mut request = fake("GET", "/api/ws");
+        let headers = request.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        let response = proxy.serve(request, &client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(body_text(response)
+            .await
+            .contains("no response after 200ms"));
+    }
+
+    #[tokio::test]
+    async fn proxy_retries() {
+        use std::io::{BufRead, BufReader, Write};
+        // drops the first connection without answering, like a backend restarting
+        let backend = || {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            std::thread::spawn(move || {
+                for (i, stream) in listener.incoming().enumerate() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(&stream);
+                    let mut line = String::new();
+                    while line != "\r\n" {
+                        line.clear();
+                        if reader.read_line(&mut line).unwrap() == 0 {
+                            break;
+                        }
+                    }
+                    if i > 0 {
+                        stream
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .unwrap();
+                    }
+                }
+            });
+            addr
+        };
+        let client = client();
+        let proxy = proxy_to(backend(), None, 1);
+        let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, "ok");
+
+        let proxy = proxy_to(backend(), None, 1);
+        let response = proxy.serve(fake("POST", "/api"), &client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn proxy_failover() {
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let alive = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = hyper::service::service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(Response::new(Body::from("alive")))
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+        let proxy = ProxyConfig::new(
+            "/api",
+            &ProxyTarget {
+                targets: vec![format!("http://{}", dead), format!("http://{}", alive)],
+                balance: crate::config::Balance::FirstHealthy,
+                max_failures: Some(1),
+                retries: 1,
+                ..Default::default()
+            },
+            None,
+            &cache(),
+        )
+        .unwrap();
+        let client = client();
+        // the retry goes to the other target, which is then used directly
+        for _ in 0..2 {
+            let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+            assert_eq!(body_text(response).await, "alive");
+        }
+        // the dead target is marked down, so requests that can't be retried avoid it too
+        let response = proxy.serve(fake("POST", "/api"), &client).await.unwrap();
+        assert_eq!(body_text(response).await, "alive");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn proxy_unix_target() {
+        let path =
+            std::env::temp_dir().join(format!("spa-server-proxy-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = hyper::service::service_fn(|request: Request| async move {
+                    let body = format!("unix {}", request.uri());
+                    Ok::<_, std::convert::Infallible>(Response::new(Body::from(body)))
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+        let proxy = ProxyConfig::new(
+            "/api",
+            &ProxyTarget {
+                target: format!("unix://{}", path.display()),
+                strip_prefix: true,
+                ..Default::default()
+            },
+            None,
+            &cache(),
+        )
+        .unwrap();
+        let client = client();
+        let response = proxy
+            .serve(fake("GET", "/api/users?page=2"), &client)
+            .await
+            .unwrap();
+        assert_eq!(body_text(response).await, "unix /users?page=2");
+
+        std::fs::remove_file(&path).unwrap();
+        let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let target = ProxyTarget {
+            target: "unix://".to_owned(),
+            ..Default::default()
+        };
+        assert!(ProxyConfig::new("/api", &target, None, &cache()).is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_binary_headers() {
+        use std::io::{BufRead, BufReader, Write};
+        // a backend sending a file name in Latin-1
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\n\
+                        Content-Disposition: attachment; filename=\"caf\xe9.txt\"\r\n\
+                        Content-Length: 2\r\n\r\nok",
+                    )
+                    .unwrap();
+            }
+        });
+        let disposition = &b"attachment; filename=\"caf\xe9.txt\""[..];
+        let client = client();
+        let proxy = proxy_to(addr, None, 0);
+        let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_DISPOSITION].as_bytes(),
+            disposition
+        );
+
+        let folder = std::env::temp_dir().join(format!(
+            "spa-server-test-binary-headers-{}",
+            std::process::id()
+        ));
+        let recorded = |mode| {
+            let target = ProxyTarget {
+                target: format!("http://{}", addr),
+                record: Some(crate::config::RecordConfig {
+                    mode,
+                    match_on: vec![crate::config::RecordMatch::Path],
+                    folder: Some(folder.display().to_string()),
+                }),
+                ..Default::default()
+            };
+            ProxyConfig::new("/api", &target, None, &cache()).unwrap()
+        };
+        for mode in [RecordMode::Record, RecordMode::Replay] {
+            let proxy = recorded(mode);
+            let response = proxy.serve(fake("GET", "/api"), &client).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[header::CONTENT_DISPOSITION].as_bytes(),
+                disposition
+            );
+            assert_eq!(body_text(response).await, "ok");
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn proxy_event_stream() {
+        use futures_util::StreamExt as _;
+        use hyper::body::Bytes;
+        use tokio::sync::{mpsc, Mutex};
+        // sends the second event only once the first one went through the proxy
+        let (next, received) = mpsc::channel::<()>(1);
+        let received = std::sync::Arc::new(Mutex::new(received));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let received = received.clone();
+                let service = hyper::service::service_fn(move |request: Request| {
+                    let received = received.clone();
+                    async move {
+                        let encoding = request.headers()[header::ACCEPT_ENCODING].clone();
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            let mut received = received.lock().await;
+                            for i in 0..2 {
+                                let event = format!("data: {}\n\n", i);
+                                sender.send_data(Bytes::from(event)).await.unwrap();
+                                received.recv().await;
+                            }
+                        });
+                        let response = hyper::Response::builder()
+                            .header(header::CONTENT_TYPE, "text/event-stream")
+                            .header("x-accept-encoding", encoding)
+                            .body(body);
+                        Ok::<_, std::convert::Infallible>(response.unwrap())
+                    }
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+
+        let folder = std::env::temp_dir().join(format!(
+            "spa-server-test-event-stream-{}",
+            std::process::id()
+        ));
+        let recorded = ProxyTarget {
+            target: format!("http://{}", addr),
+            record: Some(crate::config::RecordConfig {
+                mode: RecordMode::Record,
+                match_on: vec![crate::config::RecordMatch::Path],
+                folder: Some(folder.display().to_string()),
+            }),
+            ..Default::default()
+        };
+        let proxies = [
+            proxy_to(addr, Some(1000), 0),
+            ProxyConfig::new("/api", &recorded, None, &cache()).unwrap(),
+        ];
+        let client = client();
+        for proxy in &proxies {
+            let mut request = fake("GET", "/api/events");
+            request.headers_mut().insert(
+                header::ACCEPT,
+                HeaderValue::from_static("text/event-stream"),
+            );
+            let response = proxy.serve(request, &client).await.unwrap();
+            assert_eq!(response.headers()["x-accept-encoding"], "identity");
+            assert_eq!(response.headers()["x-accel-buffering"], "no");
+            let mut body = response.into_body();
+            let mut events = String::new();
+            while events.matches("\n\n").count() < 2 {
+                let chunk = tokio::time::timeout(Duration::from_secs(2), body.next())
+                    .await
+                    .expect("the event was not forwarded")
+                    .unwrap()
+                    .unwrap();
+                events += std::str::from_utf8(&chunk).unwrap();
+                next.send(()).await.unwrap();
+            }
+            assert_eq!(events, "data: 0\n\ndata: 1\n\n");
+        }
+        assert_eq!(std::fs::read_dir(&folder).unwrap().count(), 0);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn proxy_is_event_stream() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(is_event_stream(
+            &headers("text/event-stream"),
+            header::ACCEPT
+        ));
+        assert!(is_event_stream(
+            &headers("text/html, Text/Event-Stream; q=0.9"),
+            header::ACCEPT
+        ));
+        assert!(!is_event_stream(&headers("text/html"), header::ACCEPT));
+        assert!(!is_event_stream(
+            &headers("text/event-stream"),
+            header::CONTENT_TYPE
+        ));
+    }
+
+    /// A self-signed certificate for localhost, written to PEM files.
+    #[cfg(feature = "tls")]
+    fn self_signed(name: &str) -> (openssl::x509::X509, std::path::PathBuf, std::path::PathBuf) {
+        use openssl::{
+            asn1::Asn1Time, bn::BigNum, hash::MessageDigest, pkey::PKey, rsa::Rsa,
+            x509::extension::SubjectAlternativeName, x509::X509NameBuilder, x509::X509,
+        };
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&cert.x509v3_context(None, None))
+            .unwrap();
+        cert.append_extension(san).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let folder = std::env::temp_dir();
+        let prefix = format!("spa-server-test-{}-{}", name, std::process::id());
+        let cert_file = folder.join(format!("{}.crt", prefix));
+        let key_file = folder.join(format!("{}.key", prefix));
+        std::fs::write(&cert_file, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert, cert_file, key_file)
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn proxy_upstream_tls() {
+        use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+        let (_, server_cert, server_key) = self_signed("server");
+        let (client_cert, client_cert_file, client_key_file) = self_signed("client");
+
+        // answers with the name of the client certificate, if any
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        acceptor.set_certificate_chain_file(&server_cert).unwrap();
+        acceptor
+            .set_private_key_file(&server_key, SslFiletype::PEM)
+            .unwrap();
+        acceptor.cert_store_mut().add_cert(client_cert).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER);
+        let acceptor = acceptor.build();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let ssl = openssl::ssl::Ssl::new(acceptor.context()).unwrap();
+                let mut stream = tokio_openssl::SslStream::new(ssl, stream).unwrap();
+                if std::pin::Pin::new(&mut stream).accept().await.is_err() {
+                    continue;
+                }
+                let client = stream
+                    .ssl()
+                    .peer_certificate()
+                    .and_then(|cert| {
+                        let entry = cert.subject_name().entries().next()?;
+                        entry.data().to_string().ok()
+                    })
+                    .unwrap_or_else(|| "none".to_owned());
+                let service = hyper::service::service_fn(move |_| {
+                    let body = format!("client={}", client);
+                    async move { Ok::<_, std::convert::Infallible>(Response::new(Body::from(body))) }
+                });
+                tokio::spawn(hyper::server::conn::Http::new().serve_connection(stream, service));
+            }
+        });
+
+        let path = |path: &std::path::Path| Some(path.display().to_string());
+        let proxy = |ca_file: Option<&std::path::Path>,
+                     insecure_skip_verify: bool,
+                     client: Option<(&std::path::Path, &std::path::Path)>| {
+            let target = ProxyTarget {
+                target: format!("https://localhost:{}", port),
+                ca_file: ca_file.and_then(path),
+                insecure_skip_verify,
+                client_cert: client.and_then(|(cert, _)| path(cert)),
+                client_key: client.and_then(|(_, key)| path(key)),
+                ..Default::default()
+            };
+            ProxyConfig::new("/api", &target, None, &cache())
+        };
+        let client = client();
+        let get = |proxy: Result<ProxyConfig>| {
+            let client = &client;
+            async move {
+                let response = proxy.unwrap().serve(fake("GET", "/api"), client).await;
+                let response = response.unwrap();
+                (response.status(), body_text(response).await)
+            }
+        };
+        let (status, _) = get(proxy(None, false, None)).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(get(proxy(None, true, None)).await.1, "client=none");
+        assert_eq!(
+            get(proxy(Some(&server_cert), false, None)).await.1,
+            "client=none"
+        );
+        let client_pair = (client_cert_file.as_path(), client_key_file.as_path());
+        assert_eq!(
+            get(proxy(Some(&server_cert), false, Some(client_pair)))
+                .await
+                .1,
+            "client=client"
+        );
+
+        let wrong_key = (client_cert_file.as_path(), server_key.as_path());
+        assert!(proxy(Some(&server_cert), false, Some(wrong_key)).is_err());
+        assert!(proxy(Some(std::path::Path::new("/missing/ca.crt")), false, None).is_err());
+        let target = ProxyTarget {
+            target: format!("https://localhost:{}", port),
+            client_cert: path(&client_cert_file),
+            ..Default::default()
+        };
+        assert!(ProxyConfig::new("/api", &target, None, &cache()).is_err());
+        for file in [server_cert, server_key, client_cert_file, client_key_file] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn proxy_upstream_tls_disabled() {
+        let target = ProxyTarget {
+            target: "https://localhost:8443".to_owned(),
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        assert!(ProxyConfig::new("/api", &target, None, &cache()).is_err());
+    }
+
+    #[test]
+    fn proxy_retry_delay() {
+        assert_eq!(retry_delay(0), RETRY_DELAY);
+        assert_eq!(retry_delay(2), RETRY_DELAY * 4);
+        assert_eq!(retry_delay(6), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[tokio::test]
+    async fn read_body_limit() {
+        let body = || Body::from("hello");
+        assert_eq!(
+            read_body(body(), Some(5)).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
+        assert_eq!(read_body(body(), Some(4)).await.unwrap(), None);
+        assert_eq!(
+            read_body(body(), None).await.unwrap(),
+            Some(b"hello".to_vec())
+        );
     }
 }
@@ -0,0 +1,282 @@
+use super::Response;
+use crate::{
+    cache::{Cache, CacheKind},
+    config::{RecordConfig, RecordMatch, RecordMode},
+};
+use anyhow::{Context, Result};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fs, io, path::PathBuf};
+
+#[derive(Debug)]
+pub struct Recorder {
+    pub folder: PathBuf,
+    pub mode: RecordMode,
+    pub match_on: Vec<RecordMatch>,
+}
+
+/// A recorded response, stored as json on disk, with its body encoded in base64. The header
+/// values are decoded as Latin-1, so the bytes of any value are kept, even if it is not text.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Recorder {
+    pub fn new(config: &RecordConfig, proxy_path: &str, cache: &Cache) -> Result<Self> {
+        let folder = if let Some(folder) = &config.folder {
+            let folder = shellexpand::full(folder)
+                .with_context(|| format!("failed to expand path: {}", folder))?;
+            let folder = PathBuf::from(folder.as_ref());
+            fs::create_dir_all(&folder).with_context(|| {
+                format!("failed to create recording folder: {}", folder.display())
+            })?;
+            folder
+        } else {
+            cache
+                .persistent_resource(CacheKind::Recording, &[proxy_path.as_bytes()])
+                .context("failed to setup folder for recordings")?
+        };
+        debug!("recordings for {}: {}", proxy_path, folder.display());
+        Ok(Self {
+            folder,
+            mode: config.mode,
+            match_on: config.match_on.clone(),
+        })
+    }
+
+    /// Compute the key identifying a request, only using the parts listed in `match_on`.
+    pub fn key(&self, method: &str, raw_url: &str, body: &[u8]) -> String {
+        let mut parts = raw_url.splitn(2, '?');
+        let path = parts.next().unwrap_or_default();
+        let query = parts.next().unwrap_or_default();
+        let mut hash = Fnv::new();
+        for part in &[
+            RecordMatch::Method,
+            RecordMatch::Path,
+            RecordMatch::Query,
+            RecordMatch::Body,
+        ] {
+            if !self.match_on.contains(part) {
+                continue;
+            }
+            match part {
+                RecordMatch::Method => hash.write(method.as_bytes()),
+                RecordMatch::Path => hash.write(path.as_bytes()),
+                RecordMatch::Query => hash.write(query.as_bytes()),
+                RecordMatch::Body => hash.write(body),
+            }
+            hash.write(&[0]);
+        }
+        format!("{:016x}", hash.finish())
+    }
+
+    pub fn load(&self, key: &str) -> Result<Option<Recording>> {
+        let path = self.path(key);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to read recording: {}", path.display()))
+            }
+        };
+        serde_json::from_slice(&content)
+            .map(Some)
+            .with_context(|| format!("invalid recording: {}", path.display()))
+    }
+
+    pub fn save(&self, key: &str, recording: &Recording) -> Result<()> {
+        let path = self.path(key);
+        trace!("saving recording to {}", path.display());
+        let content = serde_json::to_vec_pretty(recording).context("failed to save recording")?;
+        fs::write(&path, content)
+            .with_context(|| format!("failed to write recording: {}", path.display()))
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.folder.join(format!("{}.json", key))
+    }
+}
+
+impl Recording {
+    pub fn into_response(self) -> Result<Response> {
+        let body = base64::decode(&self.body).context("invalid body in recording")?;
+        let mut response = Response::new(body.into());
+        *response.status_mut() =
+            hyper::StatusCode::from_u16(self.status).context("invalid status in recording")?;
+        for (key, value) in self.headers {
+            response.headers_mut().append(
+                HeaderName::from_bytes(key.as_bytes()).context("invalid header in recording")?,
+                header_value(&value)
+                    .with_context(|| format!("invalid header `{}` in recording", key))?,
+            );
+        }
+        Ok(response)
+    }
+}
+
+/// The headers of a response, as stored in a recording.
+pub fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .as_bytes()
+                .iter()
+                .map(|&byte| char::from(byte))
+                .collect();
+            (key.as_str().to_owned(), value)
+        })
+        .collect()
+}
+
+/// Encode a recorded header value back to Latin-1. Characters outside of it can only come from
+/// a recording edited by hand, they are rejected rather than guessing an encoding.
+fn header_value(value: &str) -> Result<HeaderValue> {
+    let bytes = value
+        .chars()
+        .map(|c| u8::try_from(u32::from(c)))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("`{}` is not Latin-1", value))?;
+    Ok(HeaderValue::from_bytes(&bytes)?)
+}
+
+/// FNV-1a, used instead of `DefaultHasher` as the keys must stay the same across versions.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder(match_on: Vec<RecordMatch>) -> Recorder {
+        Recorder {
+            folder: PathBuf::new(),
+            mode: RecordMode::Replay,
+            match_on,
+        }
+    }
+
+    #[test]
+    fn recorder_key() {
+        let r = recorder(vec![RecordMatch::Method, RecordMatch::Path]);
+        assert_eq!(
+            r.key("GET", "/api/users?page=1", b""),
+            r.key("GET", "/api/users?page=2", b"body")
+        );
+        assert_ne!(
+            r.key("GET", "/api/users", b""),
+            r.key("POST", "/api/users", b"")
+        );
+        assert_ne!(
+            r.key("GET", "/api/users", b""),
+            r.key("GET", "/api/posts", b"")
+        );
+
+        let r = recorder(vec![
+            RecordMatch::Path,
+            RecordMatch::Query,
+            RecordMatch::Body,
+        ]);
+        assert_eq!(
+            r.key("GET", "/api/users?page=1", b""),
+            r.key("POST", "/api/users?page=1", b"")
+        );
+        assert_ne!(
+            r.key("GET", "/api/users?page=1", b""),
+            r.key("GET", "/api/users?page=2", b"")
+        );
+        assert_ne!(
+            r.key("POST", "/api/users", b"a"),
+            r.key("POST", "/api/users", b"b")
+        );
+        // the separator prevents collisions between parts
+        assert_ne!(
+            r.key("GET", "/api/users?a", b"b"),
+            r.key("GET", "/api/users?ab", b"")
+        );
+    }
+
+    #[test]
+    fn recording_round_trip() {
+        let folder = std::env::temp_dir().join("spa-server-test-recording-round-trip");
+        fs::create_dir_all(&folder).unwrap();
+        let r = Recorder {
+            folder: folder.clone(),
+            mode: RecordMode::Record,
+            match_on: vec![RecordMatch::Path],
+        };
+        let key = r.key("GET", "/api/users", b"");
+        fs::remove_file(r.path(&key)).ok();
+        assert_eq!(r.load(&key).unwrap(), None);
+        let recording = Recording {
+            method: "GET".to_owned(),
+            url: "/api/users".to_owned(),
+            status: 201,
+            headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+            body: base64::encode("[]"),
+        };
+        r.save(&key, &recording).unwrap();
+        assert_eq!(r.load(&key).unwrap(), Some(recording));
+        fs::remove_dir_all(folder).ok();
+    }
+
+    #[test]
+    fn recording_binary_headers() {
+        let mut headers = HeaderMap::new();
+        let disposition = HeaderValue::from_bytes(b"attachment; filename=\"caf\xe9.txt\"").unwrap();
+        headers.insert("content-disposition", disposition.clone());
+        headers.insert("x-utf8", HeaderValue::from_bytes("é".as_bytes()).unwrap());
+        let pairs = header_pairs(&headers);
+        assert_eq!(
+            pairs[0],
+            (
+                "content-disposition".to_owned(),
+                "attachment; filename=\"café.txt\"".to_owned()
+            )
+        );
+
+        let recording = Recording {
+            method: "GET".to_owned(),
+            url: "/api/file".to_owned(),
+            status: 200,
+            headers: pairs.clone(),
+            body: String::new(),
+        };
+        let response = recording.into_response().unwrap();
+        assert_eq!(response.headers()["content-disposition"], disposition);
+        assert_eq!(response.headers()["x-utf8"].as_bytes(), "é".as_bytes());
+        // UTF-8 bytes are stored as Latin-1 too, and replayed as they were
+        assert_eq!(pairs[1], ("x-utf8".to_owned(), "\u{c3}\u{a9}".to_owned()));
+        assert_eq!(header_value("café").unwrap().as_bytes(), b"caf\xe9");
+        // edited by hand
+        assert!(header_value("→").is_err());
+        let recording = Recording {
+            method: "GET".to_owned(),
+            url: "/api/file".to_owned(),
+            status: 200,
+            headers: vec![("x-arrow".to_owned(), "→".to_owned())],
+            body: String::new(),
+        };
+        assert!(recording.into_response().is_err());
+    }
+}
@@ -0,0 +1,33 @@
+use super::Response;
+use hyper::{header, Body, StatusCode};
+use serde::Serialize;
+
+pub fn empty(status: StatusCode) -> Response {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+pub fn text(status: StatusCode, content: impl Into<String>) -> Response {
+    let mut response = Response::new(Body::from(content.into()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+pub fn json<T: Serialize>(value: &T) -> Response {
+    let content = serde_json::to_string(value).expect("failed to serialize json");
+    let mut response = Response::new(Body::from(content));
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+pub fn not_found() -> Response {
+    empty(StatusCode::NOT_FOUND)
+}
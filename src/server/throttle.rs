@@ -0,0 +1,90 @@
+use super::Response;
+use crate::config::NetworkProfile;
+use futures_util::stream;
+use hyper::body::{Body, Bytes, HttpBody};
+use std::time::{Duration, Instant};
+
+/// A body limiting the rate at which its inner body is sent.
+struct Throttled {
+    inner: Body,
+    bytes_per_sec: u64,
+    start: Option<Instant>,
+    sent: u64,
+    pending: Bytes,
+}
+
+impl Throttled {
+    fn new(inner: Body, bytes_per_sec: u64) -> Self {
+        Self {
+            inner,
+            bytes_per_sec: bytes_per_sec.max(1),
+            start: None,
+            sent: 0,
+            pending: Bytes::new(),
+        }
+    }
+
+    async fn next_chunk(&mut self) -> Option<Result<Bytes, hyper::Error>> {
+        if self.pending.is_empty() {
+            match self.inner.data().await? {
+                Ok(data) => self.pending = data,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let start = *self.start.get_or_insert_with(Instant::now);
+        // send at most 100ms worth of data at once, to keep the rate smooth
+        let size = self
+            .pending
+            .len()
+            .min((self.bytes_per_sec / 10).max(1) as usize);
+        let chunk = self.pending.split_to(size);
+        self.sent += size as u64;
+        let expected = Duration::from_secs_f64(self.sent as f64 / self.bytes_per_sec as f64);
+        let elapsed = start.elapsed();
+        if expected > elapsed {
+            tokio::time::sleep(expected - elapsed).await;
+        }
+        Some(Ok(chunk))
+    }
+
+    fn into_body(self) -> Body {
+        Body::wrap_stream(stream::unfold(self, |mut throttled| async move {
+            let chunk = throttled.next_chunk().await?;
+            Some((chunk, throttled))
+        }))
+    }
+}
+
+/// Limit the rate at which the body of the response is sent.
+pub fn throttle(response: Response, bytes_per_sec: u64) -> Response {
+    response.map(|body| Throttled::new(body, bytes_per_sec).into_body())
+}
+
+/// Apply the latency of the profile, and limit the bandwidth of the response.
+pub async fn apply_profile(response: Response, profile: &NetworkProfile) -> Response {
+    if profile.latency() > 0 {
+        tokio::time::sleep(Duration::from_millis(profile.latency())).await;
+    }
+    throttle(response, profile.kbps() * 1000 / 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    async fn chunks(body: Body) -> Vec<Bytes> {
+        body.map(|chunk| chunk.unwrap()).collect().await
+    }
+
+    #[tokio::test]
+    async fn throttled_body() {
+        let data = vec![0u8; 300];
+        let start = Instant::now();
+        let body = Throttled::new(Body::from(data.clone()), 1000).into_body();
+        let chunks = chunks(body).await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), data);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+}
@@ -0,0 +1,102 @@
+use super::upload;
+use hyper::{body::HttpBody as _, client::conn, header, Body};
+use isahc::http;
+use std::{io, path::Path};
+use tokio::net::UnixStream;
+use tokio_util::{compat::FuturesAsyncReadCompatExt as _, io::ReaderStream};
+
+/// Send a request to a target listening on the unix socket at `path`, which the http client
+/// can't reach, on a connection of its own. Both bodies are streamed.
+pub async fn send(
+    path: &Path,
+    request: http::Request<isahc::Body>,
+) -> io::Result<http::Response<isahc::Body>> {
+    let (mut parts, body) = request.into_parts();
+    parts.uri = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .parse()
+        .expect("a path is a valid uri");
+    parts
+        .headers
+        .entry(header::HOST)
+        .or_insert_with(|| header::HeaderValue::from_static("localhost"));
+    if let Some(len) = body.len() {
+        parts.headers.insert(header::CONTENT_LENGTH, len.into());
+    }
+    let body = if body.is_empty() {
+        Body::empty()
+    } else {
+        Body::wrap_stream(ReaderStream::new(body.compat()))
+    };
+
+    let stream = UnixStream::connect(path).await?;
+    let (mut sender, connection) = conn::handshake(stream).await.map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("unix socket connection failed: {}", e);
+        }
+    });
+    let response = sender
+        .send_request(hyper::Request::from_parts(parts, body))
+        .await
+        .map_err(io::Error::other)?;
+
+    let (parts, body) = response.into_parts();
+    let len = body.size_hint().exact();
+    let (body, _) = upload::stream(body, len, None);
+    Ok(http::Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::io::AsyncReadExt as _;
+    use hyper::{server::conn::Http, service::service_fn};
+    use std::convert::Infallible;
+    use tokio::net::UnixListener;
+
+    #[tokio::test]
+    async fn unix_send() {
+        let path =
+            std::env::temp_dir().join(format!("spa-server-upstream-{}.sock", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|request: hyper::Request<Body>| async move {
+                let line = format!(
+                    "{} {} host={}",
+                    request.method(),
+                    request.uri(),
+                    request.headers()[header::HOST].to_str().unwrap()
+                );
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let body = format!("{} body={}", line, String::from_utf8_lossy(&body));
+                Ok::<_, Infallible>(hyper::Response::new(Body::from(body)))
+            });
+            Http::new().serve_connection(stream, service).await.unwrap();
+        });
+
+        let request = http::Request::post("http://localhost/api/users?page=2")
+            .body(isahc::Body::from("hello"))
+            .unwrap();
+        let response = send(&path, request).await.unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(response.status(), 200);
+        let mut body = String::new();
+        response
+            .into_body()
+            .read_to_string(&mut body)
+            .await
+            .unwrap();
+        assert_eq!(body, "POST /api/users?page=2 host=localhost body=hello");
+
+        let error = send(&path, http::Request::new(isahc::Body::empty()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,142 @@
+use futures_util::io::AsyncRead;
+use hyper::body::{Body, Bytes, HttpBody};
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// Why the body of a request could not be sent to the upstream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    /// The body is bigger than `max_body_size`.
+    TooLarge,
+    /// The client failed to send the body, usually because it disconnected.
+    Client(String),
+}
+
+/// Tells why an upload failed, once the upstream request failed.
+#[derive(Debug, Clone, Default)]
+pub struct Upload(Arc<Mutex<Option<UploadError>>>);
+
+impl Upload {
+    pub fn error(&self) -> Option<UploadError> {
+        self.0.lock().expect("poisoned lock").clone()
+    }
+
+    fn fail(&self, error: UploadError) -> io::Error {
+        let message = match &error {
+            UploadError::TooLarge => "request body too large".to_owned(),
+            UploadError::Client(e) => format!("failed to read request body: {}", e),
+        };
+        *self.0.lock().expect("poisoned lock") = Some(error);
+        io::Error::other(message)
+    }
+}
+
+/// Stream the body of the incoming request, without buffering it. The original length is kept
+/// when known, otherwise the body is sent chunked.
+pub fn stream(mut body: Body, len: Option<u64>, limit: Option<u64>) -> (isahc::Body, Upload) {
+    let upload = Upload::default();
+    if body.is_end_stream() {
+        return (isahc::Body::empty(), upload);
+    }
+    // a small buffer, so a slow upstream slows down the client instead of filling the memory
+    let (sender, receiver) = mpsc::channel(4);
+    let pump = upload.clone();
+    tokio::spawn(async move {
+        let mut sent = 0u64;
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => {
+                    sent += chunk.len() as u64;
+                    match limit {
+                        Some(limit) if sent > limit => Err(pump.fail(UploadError::TooLarge)),
+                        _ => Ok(chunk),
+                    }
+                }
+                Err(e) => Err(pump.fail(UploadError::Client(e.to_string()))),
+            };
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let reader = ChannelReader {
+        receiver,
+        chunk: Bytes::new(),
+    };
+    let body = match len {
+        Some(len) => isahc::Body::from_reader_sized(reader, len),
+        None => isahc::Body::from_reader(reader),
+    };
+    (body, upload)
+}
+
+/// Reads the chunks sent by the task consuming the incoming body.
+struct ChannelReader {
+    receiver: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            if !self.chunk.is_empty() {
+                let size = buf.len().min(self.chunk.len());
+                buf[..size].copy_from_slice(&self.chunk.split_to(size));
+                return Poll::Ready(Ok(size));
+            }
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.chunk = chunk,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{io::AsyncReadExt, stream};
+
+    fn chunked(chunks: Vec<io::Result<&'static str>>) -> Body {
+        Body::wrap_stream(stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn upload_stream() {
+        let (mut body, upload) = stream(chunked(vec![Ok("hello "), Ok("world")]), None, None);
+        assert_eq!(body.len(), None);
+        let mut data = String::new();
+        body.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "hello world");
+        assert_eq!(upload.error(), None);
+
+        let (body, _) = stream(Body::from("hello"), Some(5), None);
+        assert_eq!(body.len(), Some(5));
+        let (body, _) = stream(Body::empty(), None, None);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_errors() {
+        let (mut body, upload) = stream(chunked(vec![Ok("hello "), Ok("world")]), None, Some(8));
+        assert!(body.read_to_end(&mut Vec::new()).await.is_err());
+        assert_eq!(upload.error(), Some(UploadError::TooLarge));
+
+        let disconnected = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let (mut body, upload) = stream(chunked(vec![Ok("hello"), Err(disconnected)]), None, None);
+        assert!(body.read_to_end(&mut Vec::new()).await.is_err());
+        assert!(matches!(upload.error(), Some(UploadError::Client(_))));
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{
+    config::{Balance, ProxyTarget},
+    tls::Connector,
+};
+use anyhow::{Context, Result};
+use hyper::header::HeaderValue;
+#[cfg(feature = "tls")]
+use isahc::config::{CaCertificate, ClientCertificate, PrivateKey, SslOption};
+use isahc::{config::Configurable as _, HttpClient, HttpClientBuilder};
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use rand::Rng;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// One of the targets of a proxy.
+#[derive(Debug)]
+pub struct Upstream {
+    pub target: String,
+    pub url: url::Url,
+    /// The `Host` sent to the target when `change_origin` is set.
+    pub host: Option<HeaderValue>,
+    /// The socket of a `unix:///run/app.sock` target.
+    pub unix: Option<PathBuf>,
+    /// The consecutive failures to reach the target.
+    failures: AtomicU32,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn new(target: &str, change_origin: bool) -> Result<Self> {
+        let url =
+            url::Url::parse(target).with_context(|| format!("invalid target: `{}`", target))?;
+        let unix = if url.scheme() == "unix" {
+            anyhow::ensure!(
+                cfg!(unix),
+                "unix sockets are not supported on this platform: {}",
+                target
+            );
+            anyhow::ensure!(url.path().len() > 1, "no socket in target: `{}`", target);
+            Some(PathBuf::from(url.path()))
+        } else {
+            None
+        };
+        let host = if unix.is_some() {
+            change_origin.then(|| HeaderValue::from_static("localhost"))
+        } else if change_origin {
+            let host = url
+                .host_str()
+                .with_context(|| format!("no host in target: `{}`", target))?;
+            let host = match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            };
+            Some(HeaderValue::from_str(&host).context("invalid host in target")?)
+        } else {
+            None
+        };
+        Ok(Self {
+            target: target.to_owned(),
+            url,
+            host,
+            unix,
+            failures: AtomicU32::new(0),
+            down_until: Mutex::new(None),
+        })
+    }
+
+    /// The url of a request to this target.
+    pub fn url(&self, path: &str) -> String {
+        match self.unix {
+            // only the path is sent on the socket
+            Some(_) => format!("http://localhost{}", path),
+            None => self.target.clone() + path,
+        }
+    }
+
+    fn is_up(&self, now: Instant) -> bool {
+        match *self.down_until.lock().expect("poisoned lock") {
+            Some(until) => until <= now,
+            None => true,
+        }
+    }
+}
+
+/// The targets of a proxy, with their passive health: a target is considered down for a while
+/// after too many consecutive failures, and the requests go to the other ones.
+#[derive(Debug)]
+pub struct Upstreams {
+    targets: Vec<Upstream>,
+    balance: Balance,
+    max_failures: u32,
+    down_time: Duration,
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    pub fn new(proxy: &ProxyTarget) -> Result<Self> {
+        let targets = std::iter::once(&proxy.target)
+            .filter(|target| !target.is_empty())
+            .chain(&proxy.targets)
+            .map(|target| Upstream::new(target, proxy.change_origin))
+            .collect::<Result<Vec<_>>>()?;
+        anyhow::ensure!(
+            targets.len() == 1 || proxy.target.is_empty(),
+            "only one of `target` and `targets` can be set"
+        );
+        anyhow::ensure!(!targets.is_empty(), "no target");
+        Ok(Self {
+            targets,
+            balance: proxy.balance,
+            max_failures: proxy.max_failures.unwrap_or(3).max(1),
+            down_time: Duration::from_millis(proxy.down_time.unwrap_or(10_000)),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Pick the target of a request among the healthy ones, or among all of them if they are all
+    /// down. `avoid` is skipped when there are other choices, so a retry goes somewhere else.
+    pub fn pick(&self, avoid: Option<&Upstream>) -> &Upstream {
+        let now = Instant::now();
+        let mut candidates = self
+            .targets
+            .iter()
+            .filter(|upstream| upstream.is_up(now))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = self.targets.iter().collect();
+        }
+        if let Some(avoid) = avoid {
+            if candidates.len() > 1 {
+                candidates.retain(|upstream| !std::ptr::eq(*upstream, avoid));
+            }
+        }
+        match self.balance {
+            Balance::FirstHealthy => candidates[0],
+            Balance::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Balance::Random => candidates[rand::thread_rng().gen_range(0..candidates.len())],
+        }
+    }
+
+    pub fn succeeded(&self, upstream: &Upstream) {
+        if upstream.failures.swap(0, Ordering::Relaxed) >= self.max_failures {
+            info!("target {} is back up", upstream.target);
+        }
+        *upstream.down_until.lock().expect("poisoned lock") = None;
+    }
+
+    /// Count a failure to reach the target, which is marked down after `max_failures` in a row.
+    /// Once it is up again, a single failure marks it down again.
+    pub fn failed(&self, upstream: &Upstream) {
+        let failures = upstream.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.max_failures {
+            if failures == self.max_failures {
+                warn!(
+                    "target {} is down after {} failures, retrying it in {:?}",
+                    upstream.target, failures, self.down_time
+                );
+            }
+            *upstream.down_until.lock().expect("poisoned lock") =
+                Some(Instant::now() + self.down_time);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn first(&self) -> &Upstream {
+        &self.targets[0]
+    }
+}
+
+/// The builder of the clients sending the requests to the targets. The bodies must not be
+/// decompressed, the `Content-Encoding` and `Content-Length` of the target are forwarded as is.
+pub fn http_client() -> HttpClientBuilder {
+    HttpClient::builder().automatic_decompression(false)
+}
+
+/// The TLS settings of the connections to the targets, when they are not the defaults. They need
+/// a client of their own, and a connector for the websockets.
+#[derive(Debug)]
+pub struct UpstreamTls {
+    pub http_client: HttpClient,
+    pub connector: Connector,
+}
+
+impl UpstreamTls {
+    pub fn new(proxy: &ProxyTarget) -> Result<Option<Self>> {
+        let expand = |path: &String| -> Result<PathBuf> {
+            let expanded = shellexpand::full(path)
+                .with_context(|| format!("failed to expand path: {}", path))?;
+            Ok(PathBuf::from(expanded.as_ref()))
+        };
+        let ca_file = proxy.ca_file.as_ref().map(expand).transpose()?;
+        let client = match (&proxy.client_cert, &proxy.client_key) {
+            (Some(cert), Some(key)) => Some((expand(cert)?, expand(key)?)),
+            (None, None) => None,
+            _ => anyhow::bail!("`client_cert` and `client_key` must be set together"),
+        };
+        if ca_file.is_none() && client.is_none() && !proxy.insecure_skip_verify {
+            return Ok(None);
+        }
+        Self::build(ca_file, client, proxy.insecure_skip_verify).map(Some)
+    }
+
+    #[cfg(feature = "tls")]
+    fn build(
+        ca_file: Option<PathBuf>,
+        client: Option<(PathBuf, PathBuf)>,
+        insecure_skip_verify: bool,
+    ) -> Result<Self> {
+        // the files are loaded by the connector right away, so they are checked at startup
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        let mut http_client = http_client();
+        if let Some(ca_file) = ca_file {
+            connector
+                .set_ca_file(&ca_file)
+                .with_context(|| format!("invalid CA file: {}", ca_file.display()))?;
+            http_client = http_client.ssl_ca_certificate(CaCertificate::file(ca_file));
+        }
+        if insecure_skip_verify {
+            connector.set_verify(SslVerifyMode::NONE);
+            http_client = http_client.ssl_options(
+                SslOption::DANGER_ACCEPT_INVALID_CERTS | SslOption::DANGER_ACCEPT_INVALID_HOSTS,
+            );
+        }
+        if let Some((cert, key)) = client {
+            connector
+                .set_certificate_chain_file(&cert)
+                .with_context(|| format!("invalid client certificate: {}", cert.display()))?;
+            connector
+                .set_private_key_file(&key, SslFiletype::PEM)
+                .with_context(|| format!("invalid client key: {}", key.display()))?;
+            connector.check_private_key().with_context(|| {
+                format!("`{}` is not the key of `{}`", key.display(), cert.display())
+            })?;
+            http_client = http_client.ssl_client_certificate(ClientCertificate::pem_file(
+                cert,
+                PrivateKey::pem_file(key, None),
+            ));
+        }
+        Ok(Self {
+            http_client: http_client.build().context("failed to build http client")?,
+            connector: connector.build(),
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn build(
+        _ca_file: Option<PathBuf>,
+        _client: Option<(PathBuf, PathBuf)>,
+        _insecure_skip_verify: bool,
+    ) -> Result<Self> {
+        anyhow::bail!("TLS settings are not supported by this build, enable the `tls` feature")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(balance: Balance) -> Upstreams {
+        Upstreams::new(&ProxyTarget {
+            targets: vec![
+                "http://localhost:8081".to_owned(),
+                "http://localhost:8082".to_owned(),
+                "http://localhost:8083".to_owned(),
+            ],
+            balance,
+            max_failures: Some(2),
+            down_time: Some(50),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn picked(upstreams: &Upstreams, count: usize) -> Vec<&str> {
+        (0..count)
+            .map(|_| upstreams.pick(None).url.port().unwrap())
+            .map(|port| match port {
+                8081 => "a",
+                8082 => "b",
+                _ => "c",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn upstream_balance() {
+        let round_robin = upstreams(Balance::RoundRobin);
+        assert_eq!(picked(&round_robin, 4), ["a", "b", "c", "a"]);
+        let first = upstreams(Balance::FirstHealthy);
+        assert_eq!(picked(&first, 2), ["a", "a"]);
+        let random = upstreams(Balance::Random);
+        assert_eq!(picked(&random, 100).len(), 100);
+
+        let a = first.first();
+        assert_eq!(first.pick(Some(a)).url.port(), Some(8082));
+    }
+
+    #[test]
+    fn upstream_health() {
+        let first = upstreams(Balance::FirstHealthy);
+        let a = first.first();
+        first.failed(a);
+        assert_eq!(picked(&first, 1), ["a"]);
+        first.failed(a);
+        assert_eq!(picked(&first, 2), ["b", "b"]);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(picked(&first, 1), ["a"]);
+        // still failing, down again right away
+        first.failed(a);
+        assert_eq!(picked(&first, 1), ["b"]);
+        first.succeeded(a);
+        assert_eq!(picked(&first, 1), ["a"]);
+
+        // all down, keep trying them
+        let round_robin = upstreams(Balance::RoundRobin);
+        for upstream in &round_robin.targets {
+            round_robin.failed(upstream);
+            round_robin.failed(upstream);
+        }
+        assert_eq!(picked(&round_robin, 3), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn upstream_new_errors() {
+        let proxy = |target: &str, targets: &[&str]| ProxyTarget {
+            target: target.to_owned(),
+            targets: targets.iter().map(|target| target.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(Upstreams::new(&proxy("", &[])).is_err());
+        assert!(Upstreams::new(&proxy("http://a", &["http://b"])).is_err());
+        assert!(Upstreams::new(&proxy("", &["http://a", "b"])).is_err());
+        assert!(Upstreams::new(&proxy("http://a", &[])).is_ok());
+        assert!(Upstreams::new(&proxy("", &["http://a", "http://b"])).is_ok());
+    }
+}
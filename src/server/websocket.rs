@@ -0,0 +1,285 @@
+use super::{Request, Response};
+use crate::tls::Connector;
+use anyhow::{Context, Result};
+use hyper::{
+    client::conn,
+    header::{self, HeaderMap},
+    Body, StatusCode,
+};
+#[cfg(feature = "tls")]
+use openssl::ssl::{SslConnector, SslMethod};
+#[cfg(feature = "tls")]
+use std::pin::Pin;
+use std::{fmt, future::Future, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// Whether the request asks to switch to the websocket protocol.
+pub fn is_upgrade(request: &Request) -> bool {
+    let has_token = |name: header::HeaderName, token: &str| {
+        request
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    has_token(header::CONNECTION, "upgrade") && has_token(header::UPGRADE, "websocket")
+}
+
+/// Forward the handshake to `path` on `target`, with the given headers, and with `tls` for a
+/// secure target if it needs other settings than the defaults. If the target accepts it, both
+/// connections are piped to each other once the response is sent to the client. The target must
+/// accept the connection within `connect_timeout`, and answer the handshake within `timeout`.
+pub async fn forward(
+    request: Request,
+    target: &str,
+    path: &str,
+    headers: HeaderMap,
+    tls: Option<&Connector>,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+) -> Result<Response> {
+    let url = url::Url::parse(target).with_context(|| format!("invalid target: `{}`", target))?;
+    // the path of a unix target is the socket
+    let base = match url.scheme() {
+        "unix" => "",
+        _ => url.path().trim_end_matches('/'),
+    };
+    let mut upstream = hyper::Request::builder()
+        .method(request.method().clone())
+        .uri(format!("{}{}", base, path))
+        .body(Body::empty())
+        .expect("failed to build request");
+    *upstream.headers_mut() = headers;
+
+    debug!("proxying websocket at {} to {}", request.uri(), target);
+    let response = within(timeout, send(upstream, &url, target, tls, connect_timeout)).await?;
+    Ok(pipe(request, response, target))
+}
+
+/// The target didn't answer within the timeouts of the proxy.
+#[derive(Debug)]
+pub struct Timeout(Duration);
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no response after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| Timeout(timeout))?,
+        None => future.await,
+    }
+}
+
+async fn send(
+    upstream: hyper::Request<Body>,
+    url: &url::Url,
+    target: &str,
+    tls: Option<&Connector>,
+    connect_timeout: Option<Duration>,
+) -> Result<Response> {
+    #[cfg(unix)]
+    {
+        if url.scheme() == "unix" {
+            let stream = within(connect_timeout, async {
+                Ok(tokio::net::UnixStream::connect(url.path()).await?)
+            })
+            .await
+            .with_context(|| format!("failed to connect to {}", target))?;
+            return handshake(stream, upstream).await;
+        }
+    }
+    let host = url
+        .host_str()
+        .with_context(|| format!("no host in target: `{}`", target))?;
+    let port = url
+        .port_or_known_default()
+        .with_context(|| format!("no port in target: `{}`", target))?;
+    let stream = within(connect_timeout, async {
+        Ok(TcpStream::connect((host, port)).await?)
+    })
+    .await
+    .with_context(|| format!("failed to connect to {}", target))?;
+    match url.scheme() {
+        #[cfg(feature = "tls")]
+        "https" | "wss" => {
+            let connector = match tls {
+                Some(connector) => connector.clone(),
+                None => SslConnector::builder(SslMethod::tls())?.build(),
+            };
+            let ssl = connector.configure()?.into_ssl(host)?;
+            let mut stream = tokio_openssl::SslStream::new(ssl, stream)?;
+            Pin::new(&mut stream)
+                .connect()
+                .await
+                .with_context(|| format!("failed TLS handshake with {}", target))?;
+            handshake(stream, upstream).await
+        }
+        #[cfg(not(feature = "tls"))]
+        "https" | "wss" => {
+            let _ = tls;
+            anyhow::bail!(
+                "secure websockets are not supported by this build, enable the `tls` feature \
+                to reach {}",
+                target
+            )
+        }
+        _ => handshake(stream, upstream).await,
+    }
+}
+
+/// The response sent to the client. If the target accepted the upgrade, both connections are
+/// piped to each other once it is sent.
+fn pipe(mut request: Request, mut response: Response, target: &str) -> Response {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        debug!("websocket refused by {}: {}", target, response.status());
+        return response;
+    }
+
+    let client = hyper::upgrade::on(&mut request);
+    let upstream = hyper::upgrade::on(&mut response);
+    tokio::spawn(async move {
+        match tokio::try_join!(client, upstream) {
+            Ok((mut client, mut upstream)) => {
+                if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                    debug!("websocket closed: {}", e);
+                }
+            }
+            Err(e) => warn!("failed to upgrade websocket: {}", e),
+        }
+    });
+    let (parts, _) = response.into_parts();
+    Response::from_parts(parts, Body::empty())
+}
+
+async fn handshake<S>(stream: S, request: hyper::Request<Body>) -> Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("websocket connection failed: {}", e);
+        }
+    });
+    Ok(sender.send_request(request).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{server::conn::Http, service::service_fn};
+    use std::convert::Infallible;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn fake(headers: &[(&str, &str)]) -> Request {
+        let builder = headers.iter().fold(
+            hyper::Request::builder().uri("/ws"),
+            |builder, (key, value)| builder.header(*key, *value),
+        );
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn websocket_is_upgrade() {
+        assert!(is_upgrade(&fake(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "websocket")
+        ])));
+        assert!(is_upgrade(&fake(&[
+            ("Connection", "keep-alive, Upgrade"),
+            ("Upgrade", "WebSocket")
+        ])));
+        assert!(!is_upgrade(&fake(&[("Upgrade", "websocket")])));
+        assert!(!is_upgrade(&fake(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "h2c")
+        ])));
+        assert!(!is_upgrade(&fake(&[])));
+    }
+
+    /// A backend accepting any upgrade, then echoing what it receives.
+    async fn echo_backend() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            assert!(String::from_utf8(request)
+                .unwrap()
+                .starts_with("GET /base/ws "));
+            stream
+                .write_all(
+                    b"HTTP/1.1 101 Switching Protocols\r\n\
+                    Connection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+        });
+        format!("http://{}/base", addr)
+    }
+
+    #[tokio::test]
+    async fn websocket_forward() {
+        let target = echo_backend().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |request| {
+                let target = target.clone();
+                async move {
+                    let path = request.uri().path().to_owned();
+                    let headers = request.headers().clone();
+                    let response = forward(request, &target, &path, headers, None, None, None)
+                        .await
+                        .unwrap();
+                    Ok::<_, Infallible>(response)
+                }
+            });
+            Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+                .unwrap();
+        });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(client.read_u8().await.unwrap());
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+        client.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        client.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+}
@@ -1,6 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::cache;
+use crate::{
+    cache,
+    provider::{FileProvider, FolderProvider},
+};
 use anyhow::Result;
 
 mod archive;
@@ -19,7 +22,7 @@ enum SourceKind {
     Http { format: http::HttpArchive },
 }
 
-pub fn detect(app_path: &str) -> Source {
+pub fn detect(app_path: &str) -> Source<'_> {
     let kind = if let Some(format) = http::detect(app_path) {
         SourceKind::Http { format }
     } else if let Some(format) = archive::detect(app_path) {
@@ -31,34 +34,45 @@ pub fn detect(app_path: &str) -> Source {
 }
 
 impl<'a> Source<'a> {
-    pub fn setup(&'a self, cache: &cache::Cache, base_folder: Option<&str>) -> Result<PathBuf> {
+    pub fn setup(
+        &'a self,
+        cache: &cache::Cache,
+        base_folder: Option<&str>,
+    ) -> Result<Box<dyn FileProvider>> {
         match &self.kind {
             SourceKind::Archive { format } => {
                 info!("serving from archive at {}", self.app_path);
-                let folder = archive::extract(self.app_path, format, cache)?;
-                if let Some(base_folder) = base_folder {
-                    let mut folder = folder;
-                    folder.push(base_folder);
-                    Ok(folder)
-                } else {
-                    Ok(folder)
+                let path = Path::new(self.app_path);
+                if let Some(provider) = archive::load_in_memory(path, format, base_folder)? {
+                    return Ok(Box::new(provider));
                 }
+                let folder = archive::extract(self.app_path, format, cache)?;
+                folder_provider(folder, base_folder)
             }
             SourceKind::Folder => {
                 info!("serving from folder {}", self.app_path);
-                Ok(PathBuf::from(self.app_path))
+                folder_provider(PathBuf::from(self.app_path), None)
             }
             SourceKind::Http { format } => {
                 info!("serving from archive located at {}", self.app_path);
-                let folder = http::extract(self.app_path, format, cache)?;
-                if let Some(base_folder) = base_folder {
-                    let mut folder = folder;
-                    folder.push(base_folder);
-                    Ok(folder)
-                } else {
-                    Ok(folder)
+                let download_path = http::download(self.app_path, format, cache)?;
+                if let Some(provider) =
+                    archive::load_in_memory(&download_path, format.format(), base_folder)?
+                {
+                    return Ok(Box::new(provider));
                 }
+                let folder = http::extract(self.app_path, &download_path, format, cache)?;
+                folder_provider(folder, base_folder)
             }
         }
     }
 }
+
+fn folder_provider(folder: PathBuf, base_folder: Option<&str>) -> Result<Box<dyn FileProvider>> {
+    let folder = if let Some(base_folder) = base_folder {
+        folder.join(base_folder)
+    } else {
+        folder
+    };
+    Ok(Box::new(FolderProvider::new(folder)?))
+}
@@ -1,10 +1,14 @@
 use std::{
-    fmt,
+    fmt, fs,
+    io::BufReader,
     path::{Component, Path, PathBuf},
     process::{Command, Stdio},
 };
 
-use crate::cache::{Cache, CacheKind};
+use crate::{
+    cache::{Cache, CacheKind},
+    provider::MemoryProvider,
+};
 use anyhow::{Context, Result};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -213,16 +217,63 @@ pub fn extract(archive_path: &str, archive: &ArchiveFormat, cache: &Cache) -> Re
         )
         .context("failed to create cache folder for extraction")?;
     debug!("path for extracted archive: {}", extracted_path.display());
-    extract_archive_to(&Path::new(archive_path), archive, &extracted_path)
+    extract_archive_to(Path::new(archive_path), archive, &extracted_path)
         .context("failed to extract archive")?;
     Ok(extracted_path)
 }
 
+/// Load the archive in memory, if its format can be read without the `tar` executable (plain or
+/// gzipped tar archives). Returns `None` for the other tar formats, which must be extracted on
+/// disk, and fails for the archives that are not tar archives, like zip.
+pub fn load_in_memory(
+    path: &Path,
+    archive: &ArchiveFormat,
+    base_folder: Option<&str>,
+) -> Result<Option<MemoryProvider>> {
+    let name = path.display().to_string();
+    let open = || {
+        fs::File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("failed to open archive at {}", path.display()))
+    };
+    match archive.kind {
+        ArchiveFormatKind::Tar => MemoryProvider::from_tar(name, open()?, base_folder).map(Some),
+        ArchiveFormatKind::TarGzip => {
+            MemoryProvider::from_tar(name, flate2::bufread::GzDecoder::new(open()?), base_folder)
+                .map(Some)
+        }
+        _ if !archive.is_tar() => anyhow::bail!(
+            "{} archives are not supported, only tar archives can be served",
+            archive
+        ),
+        _ => {
+            info!(
+                "{} archives can't be served from memory, extracting {} with `tar`",
+                archive, name
+            );
+            Ok(None)
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use ArchiveFormatKind::*;
 
+    #[test]
+    fn load_in_memory_formats() {
+        let load = |path: &str| load_in_memory(Path::new(path), &detect(path).unwrap(), None);
+        assert!(load("missing.tar.xz").unwrap().is_none());
+        assert!(load("missing.tar.zst").unwrap().is_none());
+        let error = load("missing.zip").err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "zip archives are not supported, only tar archives can be served"
+        );
+        assert!(load("missing.rar").is_err());
+    }
+
     #[test]
     fn parses_correctly() {
         assert_eq!(detect("file.tar.z"), Some(ArchiveFormat::new(6, TarZ)));
@@ -296,7 +347,7 @@ mod test {
         assert_eq!(detect("filetar.zst"), Some(ArchiveFormat::new(4, Zstd)));
     }
     fn wrap_extract_path(path: &str) -> PathBuf {
-        path_for_extraction(&Path::new(path), &detect(path).expect("archive detection"))
+        path_for_extraction(Path::new(path), &detect(path).expect("archive detection"))
     }
 
     #[cfg(unix)]
@@ -1,11 +1,14 @@
-use std::{fs, io, path::PathBuf};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
 use super::archive::{self, ArchiveFormat};
 
 use crate::cache::{Cache, CacheKind};
 use anyhow::{Context, Result};
 use isahc::http;
-use rouille::url::Url;
+use url::Url;
 
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
@@ -14,6 +17,12 @@ pub struct HttpArchive {
     format: ArchiveFormat,
 }
 
+impl HttpArchive {
+    pub fn format(&self) -> &ArchiveFormat {
+        &self.format
+    }
+}
+
 pub fn detect(app_path: &str) -> Option<HttpArchive> {
     Url::parse(app_path)
         .ok()
@@ -23,7 +32,8 @@ pub fn detect(app_path: &str) -> Option<HttpArchive> {
 }
 
 /// This function can only be called for urls that have been constructed by [`detect`](detect).
-pub fn extract(app_path: &str, format: &HttpArchive, cache: &Cache) -> Result<PathBuf> {
+/// Returns the path of the downloaded archive.
+pub fn download(app_path: &str, format: &HttpArchive, cache: &Cache) -> Result<PathBuf> {
     anyhow::ensure!(
         format.format.is_tar(),
         "got {:?} archive, only tar archives are supported",
@@ -57,7 +67,19 @@ pub fn extract(app_path: &str, format: &HttpArchive, cache: &Cache) -> Result<Pa
     );
     let mut body = response.into_body();
     io::copy(&mut body, &mut download_file).context("failed to download file")?;
+    Ok(download_path)
+}
 
+/// Extract the archive downloaded by [`download`](download), and returns the path of the
+/// extracted folder.
+pub fn extract(
+    app_path: &str,
+    download_path: &Path,
+    format: &HttpArchive,
+    cache: &Cache,
+) -> Result<PathBuf> {
+    let private_url = url_and_request(app_path).0;
+    let filename = url_filename(&private_url);
     let extract_path = cache
         .resource(
             CacheKind::Archive,
@@ -70,7 +92,7 @@ pub fn extract(app_path: &str, format: &HttpArchive, cache: &Cache) -> Result<Pa
 
     trace!("extracting to: {}", extract_path.display());
 
-    archive::extract_archive_to(&download_path, &format.format, &extract_path)
+    archive::extract_archive_to(download_path, &format.format, &extract_path)
         .context("failed to extract archive")?;
 
     Ok(extract_path)
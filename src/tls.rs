@@ -0,0 +1,13 @@
+//! The TLS types, from OpenSSL when the `tls` feature is enabled. Without it, they can't be
+//! built, so TLS settings are rejected when the config is loaded instead.
+
+#[cfg(feature = "tls")]
+pub use openssl::ssl::{SslAcceptor as Acceptor, SslConnector as Connector};
+
+#[cfg(not(feature = "tls"))]
+#[derive(Debug)]
+pub enum Acceptor {}
+
+#[cfg(not(feature = "tls"))]
+#[derive(Debug, Clone)]
+pub enum Connector {}